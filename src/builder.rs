@@ -0,0 +1,225 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Config;
+use crate::inst::{merge_env, oci_spec_from_config};
+use crate::layers::{self, Digest, LayerError};
+use crate::squash;
+use crate::reconcile::CONTS_ROOT;
+use crate::registry::{self, ConfigError, RawConfig, Registry};
+use crate::paths::{self, IdentError, MountOptionError, OverlayOptions, PathError};
+
+// builds a new layer by running a recipe in a container made from an existing config, then freezing what it wrote
+
+#[derive(Deserialize)]
+pub struct Recipe {
+    base: String, // config the build starts from
+    name: String, // config to register the result as
+    mirror: Option<Mirror>,
+    #[serde(default)]
+    env: Vec<String>, // only set during the build
+    steps: Vec<Step>
+}
+
+// a host directory (package mirror, wheels, tarballs...) visible read-only during the build
+#[derive(Deserialize)]
+struct Mirror {
+    src: String,
+    dest: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    Run(Vec<String>),
+    Copy {
+        from: String, // host path
+        to: String // container path
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum BuildError {
+    ReadRecipe(io::Error),
+    ParseRecipe(serde_json::Error),
+    LoadConfigs(io::Error),
+    Base(ConfigError),
+    Layer(LayerError),
+    CreateDir(io::Error),
+    BadName(IdentError),
+    BadCopyDest(PathError),
+    MountOptions(MountOptionError),
+    MountRoot(io::Error),
+    WriteConfig(io::Error),
+    Copy {
+        step: usize,
+        err: io::Error
+    },
+    RuncCommand(io::Error),
+    Run {
+        step: usize,
+        code: Option<i32>
+    },
+    Umount(io::Error),
+    NameTaken(String),
+    RegisterConfig(io::Error)
+}
+
+fn run_steps(recipe: &Recipe, base: &Config, dir: &Path, build_id: &str) -> Result<(), BuildError> {
+    let root = dir.join("root");
+
+    for (step_id, step) in recipe.steps.iter().enumerate() {
+        match step {
+            Step::Copy { from, to } => {
+                let to = paths::relative(Path::new(to.trim_start_matches('/'))).map_err(BuildError::BadCopyDest)?;
+
+                // not cp onto root.join(to): it would follow the image's symlinks from the host's /
+                paths::copy_into_root(Path::new(from), &root, &to).map_err(|err| BuildError::Copy {
+                    step: step_id,
+                    err
+                })?;
+            }
+            Step::Run(args) => {
+                let mut spec = oci_spec_from_config(base, build_id, &step_id.to_string());
+
+                if let Some(Value::Object(process)) = spec.get_mut("process") {
+                    process.insert("args".to_owned(), Value::from(args.clone()));
+                    process.insert("terminal".to_owned(), Value::Bool(false));
+
+                    // the recipe's replace the base's by key, since whatever reads the env takes the first it finds
+                    if let Some(Value::Array(env)) = process.get_mut("env") {
+                        let mut merged: Vec<String> = env.iter().filter_map(|var| var.as_str().map(str::to_owned)).collect();
+
+                        merge_env(&mut merged, &recipe.env);
+                        *env = merged.into_iter().map(Value::from).collect();
+                    }
+                }
+
+                if let Some(Value::Object(root)) = spec.get_mut("root") {
+                    root.insert("readonly".to_owned(), Value::Bool(false));
+                }
+
+                if let Some(mirror) = &recipe.mirror {
+                    if let Some(Value::Array(mounts)) = spec.get_mut("mounts") {
+                        mounts.push(serde_json::json!({
+                            "destination": mirror.dest,
+                            "type": "bind",
+                            "source": mirror.src,
+                            "options": ["rbind", "ro", "nosuid", "nodev"]
+                        }));
+                    }
+                }
+
+                fs::write(dir.join("config.json"), serde_json::to_string(&spec).unwrap()).map_err(BuildError::WriteConfig)?;
+
+                // foreground, so the operator sees the build output
+                let status = Command::new("/usr/bin/runc").arg("run").arg("--bundle").arg(dir).arg(format!("rto_{}_{}", build_id, step_id)).stdin(Stdio::null()).status().map_err(BuildError::RuncCommand)?;
+
+                if !status.success() {
+                    return Err(BuildError::Run {
+                        step: step_id,
+                        code: status.code()
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// returns the new layer's digest, having registered <name>.json
+pub fn build(recipe_path: &Path) -> Result<Digest, BuildError> {
+    let recipe: Recipe = serde_json::from_slice(&fs::read(recipe_path).map_err(BuildError::ReadRecipe)?).map_err(BuildError::ParseRecipe)?;
+    paths::validate_ident(&recipe.name).map_err(BuildError::BadName)?;
+
+    // a build never replaces a config, least of all its own base, which would then extend itself
+    let config_path = PathBuf::from(format!("{}/{}.json", registry::CONFIGS_ROOT, recipe.name));
+
+    if config_path.exists() {
+        return Err(BuildError::NameTaken(recipe.name));
+    }
+
+    let base = Registry::load(Path::new(registry::CONFIGS_ROOT)).map_err(BuildError::LoadConfigs)?.get(&recipe.base).map_err(BuildError::Base)?;
+
+    let lowerdirs: Vec<PathBuf> = base.diffs.iter().map(|diff| Digest::parse(diff)).collect::<Result<Vec<_>, _>>().and_then(|digests| squash::lowerdirs(&digests)).map_err(BuildError::Layer)?;
+
+    let build_id = format!("build-{:016x}", rand::random::<u64>());
+    let dir = PathBuf::from(format!("{}/{}", CONTS_ROOT, build_id));
+    let hidden = PathBuf::from(format!("{}/.{}", CONTS_ROOT, build_id));
+
+    // the owner file tells the conductor's sweeper this build is still going; the sweeper skips dot dirs, so it's in before the dir shows up under its real name
+    fs::create_dir_all(&hidden).map_err(BuildError::CreateDir)?;
+
+    if let Err(err) = fs::write(hidden.join("owner"), process::id().to_string()).and_then(|()| fs::rename(&hidden, &dir)) {
+        let _ = fs::remove_dir_all(&hidden);
+
+        return Err(BuildError::CreateDir(err));
+    }
+
+    fs::create_dir(dir.join("top")).map_err(BuildError::CreateDir)?;
+    fs::create_dir(dir.join("work")).map_err(BuildError::CreateDir)?;
+    fs::create_dir(dir.join("root")).map_err(BuildError::CreateDir)?;
+
+    // the upper dir is on disk rather than tmpfs since builds can be big, and the overlay features that leave host-specific xattrs in it are off so it's usable as a lowerdir later
+    let cs_overlay = CString::new("overlay").unwrap();
+    let cs_root = CString::new(format!("{}/root", dir.display())).unwrap();
+    let cs_options = match (OverlayOptions {
+        lowerdirs: &lowerdirs,
+        upperdir: Some(&dir.join("top")),
+        workdir: Some(&dir.join("work")),
+        flags: &["redirect_dir=off", "index=off", "metacopy=off"]
+    }).to_cstring() {
+        Ok(cs_options) => cs_options,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&dir);
+
+            return Err(BuildError::MountOptions(err));
+        }
+    };
+
+    if unsafe { libc::mount(cs_overlay.as_ptr(), cs_root.as_ptr(), cs_overlay.as_ptr(), 0, cs_options.as_ptr() as *const libc::c_void) } != 0 {
+        let err = io::Error::last_os_error();
+
+        let _ = fs::remove_dir_all(&dir);
+
+        return Err(BuildError::MountRoot(err));
+    }
+
+    let result = run_steps(&recipe, &base, &dir, &build_id);
+
+    if unsafe { libc::umount2(cs_root.as_ptr(), libc::MNT_DETACH) } != 0 {
+        let err = io::Error::last_os_error();
+
+        // leave the dir alone, something may still be using it
+        return Err(result.err().unwrap_or(BuildError::Umount(err)));
+    }
+
+    let result = result.and_then(|()| layers::import(&dir.join("top")).map_err(BuildError::Layer));
+
+    let _ = fs::remove_dir_all(&dir);
+
+    let digest = result?;
+
+    let config = RawConfig {
+        extends: Some(recipe.base.clone()),
+        diffs: vec![digest.to_string()],
+        ..RawConfig::default()
+    };
+
+    // checked again, in case the name was taken while this was building
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&config_path).map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => BuildError::NameTaken(recipe.name.clone()),
+        _ => BuildError::RegisterConfig(err)
+    })?;
+
+    file.write_all(serde_json::to_string_pretty(&config).unwrap().as_bytes()).map_err(BuildError::RegisterConfig)?;
+
+    Ok(digest)
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::fmt::Debug;
+use std::path::Path;
+
+use crate::layers::{self, Digest};
+use crate::{builder, gc, image_import};
+
+const USAGE: &str = "usage: rto-conductor layer (import <dir> | verify <digest> | list | gc [--dry-run])
+       rto-conductor image import <oci-layout-or-docker-save-tar> [<config-out>]
+       rto-conductor image build <recipe>";
+
+fn debug(err: impl Debug) -> String {
+    format!("{:?}", err)
+}
+
+// offline maintenance commands, `rto-conductor <command> ...`; returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result: Result<(), String> = match &args[..] {
+        ["layer", "import", src] => layers::import(Path::new(src)).map(|digest| println!("{}", digest)).map_err(debug),
+        ["layer", "verify", digest] => Digest::parse(digest).and_then(|digest| layers::verify(&digest)).map_err(debug),
+        ["layer", "list"] => layers::list().map(|layers| {
+            for (digest, sealed) in layers {
+                println!("{} {}", digest, if sealed { "sealed" } else { "unsealed" });
+            }
+        }).map_err(debug),
+        ["layer", "gc", rest @ ..] if matches!(rest, [] | ["--dry-run"]) => if rest.is_empty() { gc::sweep() } else { gc::report() }.map(|report| {
+            for digest in &report.kept {
+                println!("keep {}", digest);
+            }
+
+            for digest in &report.unreferenced {
+                println!("unreferenced {}", digest);
+            }
+
+            for key in &report.unreferenced_squashed {
+                println!("unreferenced squashed {}", key);
+            }
+
+            for tmp in &report.stale_tmp {
+                println!("stale {}", tmp.display());
+            }
+
+            for dir in &report.removed {
+                println!("removed {}", dir.display());
+            }
+
+            for (dir, reason) in &report.skipped {
+                println!("skipped {} ({})", dir.display(), reason);
+            }
+        }).map_err(debug),
+        ["image", "import", src, rest @ ..] if rest.len() <= 1 => image_import::import_image(Path::new(src)).map_err(debug).and_then(|config| {
+            let json = serde_json::to_string_pretty(&config).unwrap();
+
+            match rest {
+                [out] => fs::write(out, json).map_err(|err| format!("write {}: {}", out, err)),
+                _ => {
+                    println!("{}", json);
+
+                    Ok(())
+                }
+            }
+        }),
+        ["image", "build", recipe] => builder::build(Path::new(recipe)).map(|digest| println!("{}", digest)).map_err(debug),
+        _ => {
+            eprintln!("{}", USAGE);
+
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+
+            1
+        }
+    }
+}
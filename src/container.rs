@@ -1,16 +1,33 @@
 use std::fs;
-use std::io::Error;
+use std::io::{Error, ErrorKind, Read, Write};
 use std::ffi::CString;
-use std::process::{Stdio, Command};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
-enum ContainerState {
-    Initted,
+use serde_json::Value;
 
-}
+use crate::{UpperLimits, User};
+use crate::inst::merge_env;
+use crate::lifecycle::{Emitter, ExitStatus, IllegalTransition, State};
+use crate::paths::{path_cstring, MountOptionError, OverlayOptions};
+use crate::reconcile::BROKEN_MARKER;
+use crate::staging::{ClientStreams, StagingError};
+
+const KILL_POLL: Duration = Duration::from_millis(10); // how often an exec that can be killed checks whether it should be
+const QUOTA_POLL: Duration = Duration::from_millis(20); // how often the upper is checked for being full while something runs
 
+#[allow(dead_code)]
 #[derive(Debug)]
-enum CreateContainerError {
+pub enum CreateContainerError {
+    MountOptions(MountOptionError),
     CreateDir(Error),
+    CreateUpperDir(Error),
+    MountUpper(Error),
     CreateWorkDir(Error),
     CreateTopDir(Error),
     CreateRootDir(Error),
@@ -18,84 +35,801 @@ enum CreateContainerError {
     WriteConfig(Error),
     RuncCommand(Error),
     RuncWait(Error),
-    RuncCreate(Option<i32>)
+    RuncCreate(Option<i32>),
+    RolledBack // created fine, then torn down because another case failed
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum CleanupError {
+    RuncCommand(Error),
+    RuncDelete(String, Option<i32>),
+    Umount(PathBuf, Error),
+    RemoveDir(PathBuf, Error),
+    MarkBroken(PathBuf, Error)
+}
+
+// what went wrong, and whatever failed while undoing the steps that had already been done
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ContainerInitError {
+    pub cause: CreateContainerError,
+    pub cleanup: Vec<CleanupError>
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum RunError {
+    State(IllegalTransition),
+    NoStdio,
+    RuncCommand(Error),
+    RuncStart(Option<i32>),
+    ReadPid(Error),
+    Wait(Error),
+    Staging(StagingError)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ExecError {
+    NotRunning(State),
+    ReadSpec(Error),
+    ParseSpec(serde_json::Error),
+    WriteProcess(Error),
+    RuncCommand(Error),
+    Wait(Error)
+}
+
+#[derive(Debug)]
+pub enum StdinError {
+    Closed
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum DestroyError {
+    State(IllegalTransition),
+    Cleanup(Vec<CleanupError>)
+}
+
+enum Undo {
+    RuncDelete(String),
+    Umount(PathBuf),
+    RemoveDir(PathBuf)
+}
+
+// the steps done so far, undone last first if anything later fails
+struct Rollback {
+    dir: PathBuf,
+    steps: Vec<Undo>
+}
+
+impl Rollback {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            steps: Vec::new()
+        }
+    }
+
+    fn push(&mut self, undo: Undo) {
+        self.steps.push(undo);
+    }
+
+    fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    // every step gets tried, except that nothing gets removed once something has failed, since it may still be mounted or in use
+    // whatever's left behind is marked broken for the sweeper
+    fn run(mut self) -> Vec<CleanupError> {
+        let mut failures: Vec<CleanupError> = Vec::new();
+
+        while let Some(undo) = self.steps.pop() {
+            let result = match undo {
+                Undo::RuncDelete(runc_id) => runc_delete(&runc_id).map(drop),
+                Undo::Umount(path) => if unsafe { libc::umount2(path_cstring(&path).as_ptr(), libc::MNT_DETACH) } == 0 {
+                    Ok(())
+                } else {
+                    Err(CleanupError::Umount(path, Error::last_os_error()))
+                },
+                Undo::RemoveDir(_) if !failures.is_empty() => continue,
+                Undo::RemoveDir(path) => fs::remove_dir_all(&path).map_err(|err| CleanupError::RemoveDir(path, err))
+            };
+
+            if let Err(err) = result {
+                failures.push(err);
+            }
+        }
+
+        if !failures.is_empty() && self.dir.is_dir() {
+            if let Err(err) = fs::write(self.dir.join(BROKEN_MARKER), format!("{:?}\n", failures)) {
+                failures.push(CleanupError::MarkBroken(self.dir.clone(), err));
+            }
+        }
+
+        failures
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Outcome {
+    Finished,
+    DiskQuotaExceeded,
+    Failed // a staged run failed, by its directive's codes
+}
+
+impl Outcome {
+    // for a case that ran on top of a base that went the other way: a full disk outweighs a failed run, which outweighs finishing
+    pub fn worst(self, other: Outcome) -> Outcome {
+        match (self, other) {
+            (Outcome::DiskQuotaExceeded, _) | (_, Outcome::DiskQuotaExceeded) => Outcome::DiskQuotaExceeded,
+            (Outcome::Failed, _) | (_, Outcome::Failed) => Outcome::Failed,
+            _ => Outcome::Finished
+        }
+    }
+
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Outcome::Finished => 0x00,
+            Outcome::DiskQuotaExceeded => 0x01,
+            Outcome::Failed => 0x02
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RuncMode {
+    // `runc create` at init with the conductor's pipes as its stdio, `runc start` when started
+    // runc exits right away, so the process gets reparented to the conductor (a subreaper) and is waited on by pid
+    Detached,
+    // `runc run` when started, which stays the process's parent and exits with its status; stdin is left open for the client
+    Foreground
+}
+
+// a process to run in a container next to its own; whatever's left out comes from the container's spec
+#[derive(Clone, Default)]
+pub struct ProcessSpec {
+    pub args: Vec<String>,
+    pub env: Vec<String>, // KEY=value, layered over the container's env
+    pub cwd: Option<String>,
+    pub user: Option<User>
+}
+
+struct Pipes {
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    stderr: ChildStderr
+}
+
+// whether the upper tmpfs has filled up, in bytes or inodes
+// the ENOSPC goes to the writer and never to us, so it's sampled while the container runs and latched; something that fills it and deletes again between two samples still goes unnoticed
+struct QuotaWatch {
+    upper: PathBuf,
+    hit: AtomicBool
+}
+
+impl QuotaWatch {
+    fn new(upper: PathBuf) -> Self {
+        Self {
+            upper,
+            hit: AtomicBool::new(false)
+        }
+    }
+
+    // whether it's full now or ever was
+    fn check(&self) -> bool {
+        if !self.hit.load(Ordering::Relaxed) && full(&self.upper) {
+            self.hit.store(true, Ordering::Relaxed);
+        }
+
+        self.hit.load(Ordering::Relaxed)
+    }
+
+    // run, sampling the upper all the while
+    fn watch<T>(&self, run: impl FnOnce() -> T) -> T {
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| while !done.load(Ordering::Relaxed) {
+                self.check();
+                thread::sleep(QUOTA_POLL);
+            });
+
+            let result = run();
+
+            done.store(true, Ordering::Relaxed);
+
+            result
+        })
+    }
+}
+
+fn full(upper: &Path) -> bool {
+    let cs_upper = path_cstring(upper);
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(cs_upper.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+
+    stat.f_bavail == 0 || stat.f_favail == 0
 }
 
 pub struct Container {
-    inst_id: String,
-    id: String
+    emitter: Emitter,
+    id: usize,
+    runc_mode: RuncMode,
+    waited: AtomicBool, // its process is run_detached's to reap, not the orphan reaper's
+    state: Mutex<State>,
+    pipes: Mutex<Option<Pipes>>, // handed to runc create, until start takes them
+    stdin: Mutex<Option<Sender<Vec<u8>>>>, // to the thread writing the process's stdin, for as long as it can still be written to
+    execs: AtomicUsize, // started so far, and so the next exec's id
+    streams: ClientStreams,
+    staged_pipes: ClientStreams, // between the processes staging runs here and in the containers it spawns
+    quota: QuotaWatch
 }
 
 impl Container {
-    pub fn init(inst_id: String, id: String, diffs: &[String], config: String) -> Result<Self, CreateContainerError> { // TODO: errtype
-        fn lowerdir_from_diffs(diffs: &[String]) -> String {
-            let mut lowerdir = String::new();
-            let mut is_first: bool = true;
-            
-            for diff in diffs {
-                if is_first {
-                    is_first = false;
-                } else {
-                    lowerdir.push(':');
-                }
+    pub fn init(emitter: Emitter, id: usize, runc_mode: RuncMode, layers: &[PathBuf], upper: &UpperLimits, config: String) -> Result<Self, ContainerInitError> {
+        let dir = PathBuf::from(format!("/rto/conts/{}/{}", emitter.inst_id(), id));
+        let mut rollback = Rollback::new(dir.clone());
+
+        match Self::create(&dir, &runc_id(emitter.inst_id(), id), runc_mode, layers, upper, config, &mut rollback) {
+            Ok(pipes) => {
+                rollback.commit();
+                emitter.container(id, State::Created);
+
+                Ok(Self {
+                    emitter,
+                    id,
+                    runc_mode,
+                    waited: AtomicBool::new(false),
+                    state: Mutex::new(State::Created),
+                    pipes: Mutex::new(pipes),
+                    stdin: Mutex::new(None),
+                    execs: AtomicUsize::new(0),
+                    streams: ClientStreams::default(),
+                    staged_pipes: ClientStreams::default(),
+                    quota: QuotaWatch::new(dir.join("upper"))
+                })
+            }
+            Err(cause) => {
+                orphan(&dir);
+
+                Err(ContainerInitError {
+                    cause,
+                    cleanup: rollback.run()
+                })
+            }
+        }
+    }
+
+    // each step that leaves something behind pushes its undo before the next one starts
+    fn create(dir: &Path, runc_id: &str, runc_mode: RuncMode, layers: &[PathBuf], upper: &UpperLimits, config: String, rollback: &mut Rollback) -> Result<Option<Pipes>, CreateContainerError> {
+        let cs_options = OverlayOptions {
+            lowerdirs: layers,
+            upperdir: Some(&dir.join("upper/top")),
+            workdir: Some(&dir.join("upper/work")),
+            // a fork's lowerdirs start with its base's upper, which the base is still mounted over; index=off keeps that a warning rather than EBUSY
+            flags: &["volatile", "index=off"]
+        }.to_cstring().map_err(CreateContainerError::MountOptions)?;
+
+        fs::create_dir(dir).map_err(CreateContainerError::CreateDir)?;
+        rollback.push(Undo::RemoveDir(dir.to_owned()));
+
+        fs::create_dir(dir.join("upper")).map_err(CreateContainerError::CreateUpperDir)?;
+
+        // top and work go on their own tmpfs so a runaway writer fills its cap instead of the host disk
+        let cs_tmpfs = CString::new("tmpfs").unwrap();
+        let cs_upper_options = CString::new(format!("size={},nr_inodes={},mode=0755", upper.size, upper.inodes)).unwrap();
+
+        if unsafe { libc::mount(cs_tmpfs.as_ptr(), path_cstring(&dir.join("upper")).as_ptr(), cs_tmpfs.as_ptr(), libc::MS_NOSUID | libc::MS_NODEV, cs_upper_options.as_ptr() as *const libc::c_void) } != 0 {
+            return Err(CreateContainerError::MountUpper(Error::last_os_error()));
+        }
+
+        rollback.push(Undo::Umount(dir.join("upper")));
+
+        fs::create_dir(dir.join("upper/work")).map_err(CreateContainerError::CreateWorkDir)?;
+        fs::create_dir(dir.join("upper/top")).map_err(CreateContainerError::CreateTopDir)?;
+        fs::create_dir(dir.join("root")).map_err(CreateContainerError::CreateRootDir)?;
+
+        let cs_overlay = CString::new("overlay").unwrap();
+
+        if unsafe { libc::mount(cs_overlay.as_ptr(), path_cstring(&dir.join("root")).as_ptr(), cs_overlay.as_ptr(), 0, cs_options.as_ptr() as *const libc::c_void) } != 0 {
+            return Err(CreateContainerError::MountRoot(Error::last_os_error()));
+        }
+
+        rollback.push(Undo::Umount(dir.join("root")));
+
+        fs::write(dir.join("config.json"), config).map_err(CreateContainerError::WriteConfig)?;
+
+        if runc_mode == RuncMode::Foreground {
+            return Ok(None);
+        }
+
+        // the process inherits runc's stdio, so these pipes are how the conductor talks to it
+        let mut runc = Command::new("/usr/bin/runc").arg("create").arg("--bundle").arg(dir).arg("--pid-file").arg(dir.join("pid")).arg(runc_id).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(CreateContainerError::RuncCommand)?;
+
+        // runc can fail after it's registered the container, so the delete goes on before it's known how it went
+        rollback.push(Undo::RuncDelete(runc_id.to_owned()));
+
+        // taken before waiting, since wait closes stdin
+        let pipes = Pipes {
+            stdin: runc.stdin.take().unwrap(),
+            stdout: runc.stdout.take().unwrap(),
+            stderr: runc.stderr.take().unwrap()
+        };
+
+        match runc.wait().map_err(CreateContainerError::RuncWait)?.code() {
+            Some(0) => Ok(Some(pipes)),
+            code => Err(CreateContainerError::RuncCreate(code))
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        PathBuf::from(format!("/rto/conts/{}/{}", self.emitter.inst_id(), self.id))
+    }
+
+    // everything the container's written, which a fork of it gets as a read-only layer
+    pub fn upper(&self) -> PathBuf {
+        self.dir().join("upper/top")
+    }
+
+    // the container's filesystem as its processes see it
+    pub fn root(&self) -> PathBuf {
+        self.dir().join("root")
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn streams(&self) -> &ClientStreams {
+        &self.streams
+    }
+
+    pub fn pipes(&self) -> &ClientStreams {
+        &self.staged_pipes
+    }
+
+    pub fn output(&self, stream: &str, data: &[u8]) {
+        self.emitter.output(self.id, stream, data);
+    }
+
+    pub fn joined(&self, outcomes: &[u8]) {
+        self.emitter.joined(self.id, outcomes);
+    }
+    
+    pub fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+    
+    // checked and made under the lock, so two callers can't both take the same step
+    fn transition(&self, to: State) -> Result<(), IllegalTransition> {
+        let mut state = self.state.lock().unwrap();
+        
+        *state = state.check(to)?;
+        self.emitter.container(self.id, to);
+        
+        Ok(())
+    }
+
+    // runs the process to completion: input goes to its stdin, its output goes to the client as it comes
+    pub fn start(&self, input: &[u8]) -> Result<Outcome, RunError> {
+        self.transition(State::Starting).map_err(RunError::State)?;
+        
+        let result = self.quota.watch(|| match self.runc_mode {
+            RuncMode::Detached => self.run_detached(input),
+            RuncMode::Foreground => self.run_foreground(input)
+        });
+        
+        let status = match result {
+            Ok(status) => status,
+            Err(err) => {
+                // stopped underneath us, in which case it's already on its way out
+                let _ = self.transition(State::Failed);
                 
-                lowerdir += "/rto/imgs/diffs/";
-                lowerdir += diff;
+                return Err(err);
             }
-            
-            lowerdir
+        };
+        
+        self.transition(State::Exited).map_err(RunError::State)?;
+        self.emitter.exited(self.id, status);
+        
+        if self.quota.check() {
+            Ok(Outcome::DiskQuotaExceeded)
+        } else {
+            Ok(Outcome::Finished)
+        }
+    }
+    
+    // runs staging against the container instead of its own process; runc execs into a created container just fine, so that never starts
+    // run says whether any of staging's runs failed
+    pub fn stage(&self, run: impl FnOnce(&Self) -> Result<bool, StagingError>) -> Result<Outcome, RunError> {
+        self.transition(State::Starting).map_err(RunError::State)?;
+        self.transition(State::Running).map_err(RunError::State)?;
+
+        let failed = match self.quota.watch(|| run(self)) {
+            Ok(failed) => failed,
+            Err(err) => {
+                let _ = self.transition(State::Failed);
+
+                return Err(RunError::Staging(err));
+            }
+        };
+
+        self.transition(State::Exited).map_err(RunError::State)?;
+
+        if self.quota.check() {
+            Ok(Outcome::DiskQuotaExceeded)
+        } else if failed {
+            Ok(Outcome::Failed)
+        } else {
+            Ok(Outcome::Finished)
         }
+    }
+
+    fn run_detached(&self, input: &[u8]) -> Result<ExitStatus, RunError> {
+        let pipes = self.pipes.lock().unwrap().take().ok_or(RunError::NoStdio)?;
+        let status = Command::new("/usr/bin/runc").args(["start", &runc_id(self.emitter.inst_id(), self.id)]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status().map_err(RunError::RuncCommand)?;
+        
+        if !status.success() {
+            return Err(RunError::RuncStart(status.code()));
+        }
+        
+        let pid: libc::pid_t = fs::read_to_string(self.dir().join("pid")).map_err(RunError::ReadPid)?.trim().parse().map_err(|_| RunError::ReadPid(Error::from(ErrorKind::InvalidData)))?;
         
-        fs::create_dir(format!("/rto/conts/{}", id)).map_err(CreateContainerError::CreateDir)?;
+        self.waited.store(true, Ordering::Relaxed);
         
-        fn create_subdirs(inst_id: &str, id: &str) -> Result<(), CreateContainerError> {
-            fs::create_dir(format!("/rto/conts/{}/{}/work", inst_id, id)).map_err(CreateContainerError::CreateWorkDir)?;
-            fs::create_dir(format!("/rto/conts/{}/{}/top", inst_id, id)).map_err(CreateContainerError::CreateTopDir)?;
-            fs::create_dir(format!("/rto/conts/{}/{}/root", inst_id, id)).map_err(CreateContainerError::CreateRootDir)?;
+        self.transition(State::Running).map_err(RunError::State)?;
+        
+        self.communicate(pipes, input, false, || loop {
+            let mut status: libc::c_int = 0;
             
-            Ok(())
-        }
+            if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+                break Ok(ExitStatus::from_raw(status));
+            }
+            
+            let err = Error::last_os_error();
+            
+            if err.kind() != ErrorKind::Interrupted {
+                break Err(RunError::Wait(err));
+            }
+        })
+    }
+    
+    fn run_foreground(&self, input: &[u8]) -> Result<ExitStatus, RunError> {
+        let dir = self.dir();
+        let mut runc = Command::new("/usr/bin/runc").arg("run").arg("--bundle").arg(&dir).arg(runc_id(self.emitter.inst_id(), self.id)).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(RunError::RuncCommand)?;
         
-        if let Err(err) = create_subdirs(&inst_id, &id) {
-            fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
+        let pipes = Pipes {
+            stdin: runc.stdin.take().unwrap(),
+            stdout: runc.stdout.take().unwrap(),
+            stderr: runc.stderr.take().unwrap()
+        };
+        
+        self.transition(State::Running).map_err(RunError::State)?;
+        
+        self.communicate(pipes, input, true, || runc.wait().map_err(RunError::Wait).map(ExitStatus::from_runc))
+    }
     
-            return Err(err);
+    // feeds stdin and pumps stdout and stderr to the client until the process is gone
+    fn communicate(&self, pipes: Pipes, input: &[u8], keep_stdin: bool, wait: impl FnOnce() -> Result<ExitStatus, RunError>) -> Result<ExitStatus, RunError> {
+        if let Some(input_tx) = feed(pipes.stdin, input, keep_stdin) {
+            *self.stdin.lock().unwrap() = Some(input_tx);
         }
         
-        let cs_overlay = CString::new("overlay").unwrap();
-        let cs_root = CString::new(format!("/rto/conts/{}/root", id)).unwrap();
-        let cs_options = CString::new(format!("lowerdir={diffs},upperdir=/rto/conts/{id}/top,workdir=/rto/conts/{id}/work,volatile", id = id, diffs = lowerdir_from_diffs(diffs))).unwrap();
-    
-        if unsafe { libc::mount(cs_overlay.as_ptr(), cs_root.as_ptr(), cs_overlay.as_ptr(), 0, cs_options.as_ptr() as *const libc::c_void) } != 0 {
-            fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
+        let status = thread::scope(|scope| {
+            scope.spawn(|| self.pump(pipes.stdout, "stdout"));
+            scope.spawn(|| self.pump(pipes.stderr, "stderr"));
             
-            return Err(CreateContainerError::MountRoot(Error::last_os_error()));
+            wait()
+        });
+        
+        *self.stdin.lock().unwrap() = None;
+        
+        status
+    }
+    
+    fn pump(&self, mut pipe: impl Read, stream: &str) {
+        let mut buf = vec![0u8; 64 << 10];
+        
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => self.emitter.output(self.id, stream, &buf[..len]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break
+            }
         }
+    }
+    
+    // runs another process in the container while its own is running, to completion; stdin is read to the end and then closed
+    // if kill is given, the process is killed once it says so
+    // exited is set once the process is gone, and stdin has to run dry soon after: it's waited on, and it can't be waiting on a client that's not going to close it
+    pub fn exec(&self, process: &ProcessSpec, mut stdin: impl Read + Send, mut stdout: impl Write + Send, mut stderr: impl Write + Send, kill: Option<&(dyn Fn() -> bool + Sync)>, exited: &AtomicBool) -> Result<ExitStatus, ExecError> {
+        match self.state() {
+            State::Running => {}
+            state => return Err(ExecError::NotRunning(state))
+        }
+        
+        let exec_id = self.execs.fetch_add(1, Ordering::Relaxed);
+        
+        // next to config.json, outside the root the container can see
+        let process_path = self.dir().join(format!("exec-{}.json", exec_id));
+        let pid_path = self.dir().join(format!("exec-{}.pid", exec_id));
+        
+        fs::write(&process_path, self.process_json(process)?).map_err(ExecError::WriteProcess)?;
+        
+        let result = Command::new("/usr/bin/runc").arg("exec").arg("--process").arg(&process_path).arg("--pid-file").arg(&pid_path).arg(runc_id(self.emitter.inst_id(), self.id)).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(ExecError::RuncCommand).and_then(|mut runc| {
+            let mut stdin_pipe = runc.stdin.take().unwrap();
+            let mut stdout_pipe = runc.stdout.take().unwrap();
+            let mut stderr_pipe = runc.stderr.take().unwrap();
             
-        if let Err(err) = fs::write(format!("/rto/conts/{}/config.json", id), config) {
-            if unsafe { libc::umount(cs_root.as_ptr()) } != 0 { panic!() };
+            thread::scope(|scope| {
+                scope.spawn(move || {
+                    let _ = std::io::copy(&mut stdin, &mut stdin_pipe);
+                });
+                scope.spawn(|| std::io::copy(&mut stdout_pipe, &mut stdout));
+                scope.spawn(|| std::io::copy(&mut stderr_pipe, &mut stderr));
+                
+                if let Some(kill) = kill {
+                    // runc only writes the pid once the process is up, so it's tried until then
+                    scope.spawn(|| while !exited.load(Ordering::Relaxed) {
+                        if kill() {
+                            if let Some(pid) = fs::read_to_string(&pid_path).ok().and_then(|pid| pid.trim().parse::<libc::pid_t>().ok()) {
+                                unsafe { libc::kill(pid, libc::SIGKILL) };
+                                
+                                break;
+                            }
+                        }
+                        
+                        thread::sleep(KILL_POLL);
+                    });
+                }
+                
+                let status = runc.wait().map_err(ExecError::Wait).map(ExitStatus::from_runc);
+                
+                exited.store(true, Ordering::Relaxed);
+                
+                status
+            })
+        });
+        
+        let _ = fs::remove_file(&process_path);
+        let _ = fs::remove_file(&pid_path);
+        
+        let status = result?;
+        
+        self.emitter.exec_exited(self.id, exec_id, status);
+        
+        Ok(status)
+    }
     
-            fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
+    // the container's own process spec with process's changes on top
+    fn process_json(&self, process: &ProcessSpec) -> Result<String, ExecError> {
+        let spec: Value = serde_json::from_slice(&fs::read(self.dir().join("config.json")).map_err(ExecError::ReadSpec)?).map_err(ExecError::ParseSpec)?;
+        
+        let mut base = match spec.get("process") {
+            Some(Value::Object(base)) => base.clone(),
+            _ => serde_json::Map::new()
+        };
+        
+        let mut env: Vec<String> = match base.get("env") {
+            Some(Value::Array(env)) => env.iter().filter_map(|var| var.as_str().map(str::to_owned)).collect(),
+            _ => Vec::new()
+        };
+        
+        merge_env(&mut env, &process.env);
+        
+        base.insert("args".to_owned(), Value::from(process.args.clone()));
+        base.insert("env".to_owned(), Value::from(env));
+        base.insert("terminal".to_owned(), Value::Bool(false));
+        
+        if let Some(cwd) = &process.cwd {
+            base.insert("cwd".to_owned(), Value::from(cwd.as_str()));
+        }
+        
+        if let Some(user) = &process.user {
+            base.insert("user".to_owned(), user.to_oci());
+        }
+        
+        Ok(serde_json::to_string(&base).unwrap())
+    }
     
-            return Err(CreateContainerError::WriteConfig(err));
+    // more input for a running process, never waiting on it to read; empty data closes its stdin
+    pub fn write_stdin(&self, data: &[u8]) -> Result<(), StdinError> {
+        let mut stdin = self.stdin.lock().unwrap();
+        
+        if data.is_empty() {
+            return stdin.take().map(drop).ok_or(StdinError::Closed);
         }
+        
+        // queued for the writer, which only stops taking it once the process has stopped reading
+        stdin.as_ref().ok_or(StdinError::Closed)?.send(data.to_vec()).map_err(|_| StdinError::Closed)
+    }
     
-        match Command::new("/usr/bin/runc").args(["create", &format!("rto_{}", id)]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().map_err(CreateContainerError::RuncCommand)?.wait().map_err(CreateContainerError::RuncWait)?.code() {
-            Some(0) => Ok(Self {
-                inst_id,
-                id
-            }),
-            code @ (None | Some(_)) => {
-                if unsafe { libc::umount(cs_root.as_ptr()) } != 0 { panic!() };
-                
-                fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
-                
-                Err(CreateContainerError::RuncCreate(code))
+    // from any state but destroyed, killing whatever's running; anything that can't be cleaned up is marked for the sweeper and leaves the container failed
+    pub fn destroy(&self) -> Result<(), DestroyError> {
+        self.transition(State::Stopping).map_err(DestroyError::State)?;
+        
+        // anything staging is blocked on gets EOF instead of waiting on a client that's done with us
+        self.streams.shut();
+        self.staged_pipes.shut();
+        
+        let dir = self.dir();
+        let mut rollback = Rollback::new(dir.clone());
+
+        // runc delete kills a process that was never started, and it's left to us
+        if !self.waited.load(Ordering::Relaxed) {
+            orphan(&dir);
+        }
+
+        rollback.push(Undo::RemoveDir(dir.clone()));
+        rollback.push(Undo::Umount(dir.join("upper")));
+        rollback.push(Undo::Umount(dir.join("root")));
+        rollback.push(Undo::RuncDelete(runc_id(self.emitter.inst_id(), self.id)));
+
+        let failures = rollback.run();
+
+        if failures.is_empty() {
+            self.transition(State::Destroyed).map_err(DestroyError::State)
+        } else {
+            self.transition(State::Failed).map_err(DestroyError::State)?;
+            
+            Err(DestroyError::Cleanup(failures))
+        }
+    }
+}
+
+// `runc init`s that were never started, still to be reaped once runc delete has killed them
+static ORPHANS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+// nothing to do if runc create never got as far as writing the pid
+fn orphan(dir: &Path) {
+    if let Some(pid) = fs::read_to_string(dir.join("pid")).ok().and_then(|pid| pid.trim().parse().ok()) {
+        ORPHANS.lock().unwrap().push(pid);
+    }
+}
+
+// reaps orphans by pid rather than waitpid(-1), which would take statuses run_detached and Command are waiting on
+// a pid stays ours until it's reaped, so it can't have been reused; it's dropped if it was never ours at all (ECHILD)
+pub fn reap_orphans() {
+    loop {
+        ORPHANS.lock().unwrap().retain(|pid| unsafe { libc::waitpid(*pid, std::ptr::null_mut(), libc::WNOHANG) } == 0);
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// writes input to a process's stdin, and whatever's sent on the returned sender after it if keep is set; stdin's closed once the sender's dropped, or straight after input otherwise
+// not scoped: a process that never reads its stdin holds up this thread and nothing else, least of all the client's input
+// it goes once the process is gone or the client's done with it, whichever's first
+fn feed(mut stdin: impl Write + Send + 'static, input: &[u8], keep: bool) -> Option<Sender<Vec<u8>>> {
+    let (input_tx, input_rx) = mpsc::channel::<Vec<u8>>();
+
+    thread::spawn(move || {
+        for data in input_rx {
+            if stdin.write_all(&data).is_err() {
+                break;
             }
         }
+    });
+
+    let _ = input_tx.send(input.to_vec());
+
+    keep.then_some(input_tx)
+}
+
+// false if there was never a container to delete
+pub fn runc_delete(runc_id: &str) -> Result<bool, CleanupError> {
+    let status = Command::new("/usr/bin/runc").args(["delete", "--force", runc_id]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status().map_err(CleanupError::RuncCommand)?;
+
+    if status.success() {
+        return Ok(true);
     }
 
-    pub fn start(&self, inputs: &[u8]) {
-        
+    // it failing because there was never a container is fine
+    let exists = Command::new("/usr/bin/runc").args(["state", runc_id]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok_and(|status| status.success());
+
+    if !exists {
+        Ok(false)
+    } else {
+        Err(CleanupError::RuncDelete(runc_id.to_owned(), status.code()))
     }
-}
\ No newline at end of file
+}
+
+fn runc_id(inst_id: usize, id: usize) -> String {
+    format!("rto_{}_{}", inst_id, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cases_keep_their_base_outcome() {
+        let base_failed = [Outcome::Finished, Outcome::Failed, Outcome::DiskQuotaExceeded].map(|case| case.worst(Outcome::Failed));
+        let base_full = [Outcome::Finished, Outcome::Failed, Outcome::DiskQuotaExceeded].map(|case| case.worst(Outcome::DiskQuotaExceeded));
+
+        assert_eq!(base_failed, [Outcome::Failed, Outcome::Failed, Outcome::DiskQuotaExceeded]);
+        assert_eq!(base_full, [Outcome::DiskQuotaExceeded; 3]);
+        assert_eq!(Outcome::Failed.worst(Outcome::Finished), Outcome::Failed);
+    }
+
+    #[test]
+    fn fed_stdin_closes_unless_kept() {
+        let mut cat = Command::new("cat").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+
+        assert!(feed(cat.stdin.take().unwrap(), b"input", false).is_none());
+
+        // cat only exits once its stdin's closed
+        let exited = (0..500).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+
+            cat.try_wait().unwrap().is_some()
+        });
+
+        if !exited {
+            let _ = cat.kill();
+        }
+
+        let mut out = Vec::new();
+
+        cat.stdout.take().unwrap().read_to_end(&mut out).unwrap();
+        let _ = cat.wait();
+
+        assert!(exited);
+        assert_eq!(out, b"input");
+
+        let mut cat = Command::new("cat").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        let input_tx = feed(cat.stdin.take().unwrap(), b"in", true).unwrap();
+
+        input_tx.send(b"put".to_vec()).unwrap();
+        drop(input_tx);
+
+        let out = cat.wait_with_output().unwrap();
+
+        assert_eq!(out.stdout, b"input");
+    }
+
+    #[test]
+    fn quota_stays_hit_after_deletes() {
+        let upper = std::env::temp_dir().join(format!("rto-quota-{:016x}", rand::random::<u64>()));
+        let cs_tmpfs = CString::new("tmpfs").unwrap();
+        let cs_options = CString::new("size=65536,nr_inodes=16").unwrap();
+
+        fs::create_dir(&upper).unwrap();
+        assert_eq!(unsafe { libc::mount(cs_tmpfs.as_ptr(), path_cstring(&upper).as_ptr(), cs_tmpfs.as_ptr(), 0, cs_options.as_ptr() as *const libc::c_void) }, 0, "{:?}", Error::last_os_error());
+
+        let quota = QuotaWatch::new(upper.clone());
+        let before = quota.check();
+
+        // fills it, holds it long enough to be seen, then cleans up after itself the way a process catching ENOSPC might
+        let filled = quota.watch(|| {
+            let mut file = fs::File::create(upper.join("fill")).unwrap();
+            let filled = loop {
+                if let Err(err) = file.write_all(&[0; 4096]) {
+                    break err;
+                }
+            };
+
+            thread::sleep(QUOTA_POLL * 5);
+            fs::remove_file(upper.join("fill")).unwrap();
+
+            filled
+        });
+        let full_after = full(&upper);
+
+        unsafe { libc::umount2(path_cstring(&upper).as_ptr(), libc::MNT_DETACH) };
+        fs::remove_dir(&upper).unwrap();
+
+        assert!(!before);
+        assert_eq!(filled.raw_os_error(), Some(libc::ENOSPC));
+        // all a check at the end would have had to go on
+        assert!(!full_after);
+        assert!(quota.check());
+    }
+}
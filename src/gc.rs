@@ -0,0 +1,277 @@
+use std::fs;
+use std::io;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::layers::{self, Digest, LAYERS_ROOT};
+use crate::registry::{self, RawConfig};
+use crate::squash::{self, SQUASHED_ROOT};
+use crate::staging;
+
+// a layer is in use if a config file names it, an instance has recorded it (/rto/conts/<inst>/layers), or a mounted overlay has it as a lowerdir
+// squashed layers are kept while mounted or while they're the squash of some config's current stack, or of a stack an instance has recorded
+
+const STALE_TMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum GcError {
+    ReadConfigs(io::Error),
+    UnreadableConfigs(Vec<String>), // can't know what they reference, so nothing gets swept
+    ReadMounts(io::Error),
+    ReadConts(io::Error),
+    List(layers::LayerError)
+}
+
+#[derive(Default)]
+pub struct GcReport {
+    pub kept: Vec<Digest>,
+    pub unreferenced: Vec<Digest>,
+    pub unreferenced_squashed: Vec<String>,
+    pub stale_tmp: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub skipped: Vec<(PathBuf, String)> // became in use or failed to remove
+}
+
+#[derive(Default)]
+struct Refs {
+    layers: HashSet<Digest>,
+    squashed: HashSet<String>
+}
+
+pub fn unescape_mountinfo(field: &str) -> String {
+    let mut out = String::new();
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal: String = chars.by_ref().take(3).collect();
+
+            match u8::from_str_radix(&octal, 8) {
+                Ok(byte) => out.push(byte as char),
+                Err(_) => {
+                    out.push('\\');
+                    out.push_str(&octal);
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+// lowerdirs of every overlay mounted in our namespace
+fn mounted_lowerdirs() -> Result<Vec<PathBuf>, GcError> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(GcError::ReadMounts)?;
+    let mut lowerdirs: Vec<PathBuf> = Vec::new();
+
+    for line in mountinfo.lines() {
+        let Some((_, fs_part)) = line.split_once(" - ") else { continue };
+        let mut fields = fs_part.split(' ');
+
+        if fields.next() != Some("overlay") {
+            continue;
+        }
+
+        let super_options = fields.nth(1).unwrap_or("");
+
+        for option in super_options.split(',') {
+            if let Some(lowerdir) = option.strip_prefix("lowerdir=") {
+                lowerdirs.extend(unescape_mountinfo(lowerdir).split(':').map(PathBuf::from));
+            }
+        }
+    }
+
+    Ok(lowerdirs)
+}
+
+// what an instance records of the stacks it might mount: a digest per line, topmost first, with a blank line between stacks
+pub fn layers_record<'d>(stacks: impl Iterator<Item = &'d Vec<Digest>>) -> String {
+    stacks.map(|stack| stack.iter().map(|digest| format!("{}\n", digest)).collect::<String>()).collect::<Vec<_>>().join("\n")
+}
+
+// the stacks are squashed under the keys they had when the instance recorded them, whatever their configs say by now
+fn read_layers_record(record: &str, refs: &mut Refs) {
+    refs.layers.extend(record.lines().filter_map(|line| Digest::parse(line).ok()));
+
+    for stack in record.split("\n\n") {
+        if let Ok(digests) = stack.lines().map(Digest::parse).collect::<Result<Vec<_>, _>>() {
+            if !digests.is_empty() {
+                refs.squashed.insert(squash::stack_key(&digests));
+            }
+        }
+    }
+}
+
+fn collect_refs() -> Result<Refs, GcError> {
+    let mut refs = Refs::default();
+    let mut unreadable: Vec<String> = Vec::new();
+
+    for entry in fs::read_dir(registry::CONFIGS_ROOT).map_err(GcError::ReadConfigs)? {
+        let path = entry.map_err(GcError::ReadConfigs)?.path();
+
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+
+        match fs::read(&path).ok().and_then(|bytes| serde_json::from_slice::<RawConfig>(&bytes).ok()) {
+            Some(raw) => {
+                let spawned = raw.staging.iter().flat_map(|directives| staging::spawn_stacks(directives)).flatten();
+
+                refs.layers.extend(raw.diffs.iter().chain(spawned).filter_map(|diff| Digest::parse(diff).ok()));
+            }
+            None => unreadable.push(path.to_string_lossy().into_owned())
+        }
+    }
+
+    if !unreadable.is_empty() {
+        return Err(GcError::UnreadableConfigs(unreadable));
+    }
+
+    // squashes of stacks that registered configs currently resolve to
+    if let Ok(registry) = registry::Registry::load(Path::new(registry::CONFIGS_ROOT)) {
+        for (_, config) in registry.configs() {
+            for diffs in [&config.diffs[..]].into_iter().chain(staging::spawn_stacks(&config.staging)) {
+                if let Ok(digests) = diffs.iter().map(|diff| Digest::parse(diff)).collect::<Result<Vec<_>, _>>() {
+                    refs.squashed.insert(squash::stack_key(&digests));
+                }
+            }
+        }
+    }
+
+    match fs::read_dir("/rto/conts") {
+        Ok(entries) => for entry in entries {
+            let record = entry.map_err(GcError::ReadConts)?.path().join("layers");
+
+            if let Ok(record) = fs::read_to_string(record) {
+                read_layers_record(&record, &mut refs);
+            }
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(GcError::ReadConts(err))
+    }
+
+    for lowerdir in mounted_lowerdirs()? {
+        if let Ok(rest) = lowerdir.strip_prefix(format!("{}/sha256", LAYERS_ROOT)) {
+            if let Some(Ok(digest)) = rest.iter().next().map(|hex| Digest::parse(&format!("sha256:{}", hex.to_string_lossy()))) {
+                refs.layers.insert(digest);
+            }
+        } else if let Ok(rest) = lowerdir.strip_prefix(SQUASHED_ROOT) {
+            if let Some(key) = rest.iter().next() {
+                refs.squashed.insert(key.to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    Ok(refs)
+}
+
+fn stale_tmp_dirs() -> Vec<PathBuf> {
+    let mut stale: Vec<PathBuf> = Vec::new();
+
+    for tmp in [format!("{}/tmp", LAYERS_ROOT), format!("{}/tmp", SQUASHED_ROOT)] {
+        for entry in fs::read_dir(tmp).into_iter().flatten().flatten() {
+            let old = entry.metadata().and_then(|meta| meta.modified()).is_ok_and(|modified| SystemTime::now().duration_since(modified).is_ok_and(|age| age > STALE_TMP_AGE));
+
+            if old {
+                stale.push(entry.path());
+            }
+        }
+    }
+
+    stale
+}
+
+// what a sweep would remove, without removing anything
+pub fn report() -> Result<GcReport, GcError> {
+    let refs = collect_refs()?;
+    let mut report = GcReport::default();
+
+    for (digest, _) in layers::list().map_err(GcError::List)? {
+        if refs.layers.contains(&digest) {
+            report.kept.push(digest);
+        } else {
+            report.unreferenced.push(digest);
+        }
+    }
+
+    for entry in fs::read_dir(SQUASHED_ROOT).into_iter().flatten().flatten() {
+        let key = entry.file_name().to_string_lossy().into_owned();
+
+        if key != "tmp" && !key.starts_with('.') && !refs.squashed.contains(&key) {
+            report.unreferenced_squashed.push(key);
+        }
+    }
+
+    report.unreferenced_squashed.sort();
+    report.stale_tmp = stale_tmp_dirs();
+
+    Ok(report)
+}
+
+// moves the dir out of the way first, so nothing new can resolve it, then checks again before deleting; anything that turned out to be in use goes back
+fn sweep_one(dir: &Path, in_use: impl Fn(&Refs) -> bool, report: &mut GcReport) {
+    let trash = dir.with_file_name(format!(".gc-{:016x}", rand::random::<u64>()));
+
+    if let Err(err) = fs::rename(dir, &trash) {
+        report.skipped.push((dir.to_owned(), format!("{:?}", err)));
+
+        return;
+    }
+
+    match collect_refs() {
+        Ok(refs) if !in_use(&refs) => match fs::remove_dir_all(&trash) {
+            Ok(()) => report.removed.push(dir.to_owned()),
+            Err(err) => report.skipped.push((dir.to_owned(), format!("{:?}", err)))
+        },
+        result => {
+            let _ = fs::rename(&trash, dir);
+
+            report.skipped.push((dir.to_owned(), match result {
+                Ok(_) => "in use".to_owned(),
+                Err(err) => format!("{:?}", err)
+            }));
+        }
+    }
+}
+
+pub fn sweep() -> Result<GcReport, GcError> {
+    let mut report = report()?;
+
+    for digest in report.unreferenced.clone() {
+        sweep_one(&digest.dir(), |refs| refs.layers.contains(&digest), &mut report);
+    }
+
+    for key in report.unreferenced_squashed.clone() {
+        sweep_one(&Path::new(SQUASHED_ROOT).join(&key), |refs| refs.squashed.contains(&key), &mut report);
+    }
+
+    for tmp in report.stale_tmp.clone() {
+        match fs::remove_dir_all(&tmp) {
+            Ok(()) => report.removed.push(tmp),
+            Err(err) => report.skipped.push((tmp, format!("{:?}", err)))
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_keep_their_stacks() {
+        let digest = |byte: char| Digest::parse(&format!("sha256:{}", byte.to_string().repeat(64))).unwrap();
+        let stacks = [vec![digest('1'), digest('2')], vec![digest('3')]];
+        let mut refs = Refs::default();
+
+        read_layers_record(&layers_record(stacks.iter()), &mut refs);
+
+        assert_eq!(refs.layers, HashSet::from([digest('1'), digest('2'), digest('3')]));
+        assert_eq!(refs.squashed, HashSet::from([squash::stack_key(&stacks[0]), squash::stack_key(&stacks[1])]));
+    }
+}
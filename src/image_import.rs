@@ -0,0 +1,412 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use flate2::read::GzDecoder;
+
+use crate::{Config, UpperLimits, User};
+use crate::layers::{self, Digest, LayerError};
+use crate::paths::{self, PathError};
+
+// turns a local OCI image layout or a `docker save` tarball into layers in the store plus a language config
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ImportError {
+    Unpack(io::Error),
+    ReadJson(PathBuf, io::Error),
+    ParseJson(PathBuf, serde_json::Error),
+    NoManifest,
+    NoImage,
+    BadBlobDigest(String),
+    UnsupportedCompression(PathBuf),
+    OpenLayer(PathBuf, io::Error),
+    UnpackLayer(PathBuf, io::Error),
+    UnsafePath(PathError),
+    Whiteout(PathBuf, io::Error),
+    Opaque(PathBuf, io::Error),
+    UnknownUser(String),
+    UnknownGroup(String),
+    Layer(LayerError)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    layers: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    platform: Option<OciPlatform>
+}
+
+#[derive(Deserialize)]
+struct OciPlatform {
+    architecture: String,
+    os: String
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<OciDescriptor>
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    config: OciDescriptor,
+    layers: Vec<OciDescriptor>
+}
+
+#[derive(Deserialize, Default)]
+struct ImageConfigFile {
+    #[serde(default)]
+    config: ImageConfig
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ImageConfig {
+    #[serde(default)]
+    env: Option<Vec<String>>,
+    #[serde(default)]
+    working_dir: Option<String>,
+    #[serde(default)]
+    user: Option<String>
+}
+
+// layer blobs bottom first, plus the image config
+struct Image {
+    layers: Vec<PathBuf>,
+    config: ImageConfig
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, ImportError> {
+    let bytes = fs::read(path).map_err(|err| ImportError::ReadJson(path.to_owned(), err))?;
+
+    serde_json::from_slice(&bytes).map_err(|err| ImportError::ParseJson(path.to_owned(), err))
+}
+
+// digests come from the image, so they're checked before being turned into paths
+fn blob_path(root: &Path, digest: &str) -> Result<PathBuf, ImportError> {
+    let digest = Digest::parse(digest).map_err(|_| ImportError::BadBlobDigest(digest.to_owned()))?;
+
+    Ok(root.join("blobs/sha256").join(digest.hex()))
+}
+
+fn read_docker(root: &Path) -> Result<Image, ImportError> {
+    let manifests: Vec<DockerManifest> = read_json(&root.join("manifest.json"))?;
+    let manifest = manifests.into_iter().next().ok_or(ImportError::NoImage)?;
+
+    // paths inside a tarball or manifest must stay inside it, though they may start at its root
+    let in_tar = |path: &str| {
+        let path = Path::new(path);
+
+        paths::relative(path.strip_prefix("/").unwrap_or(path)).map(|rel| root.join(rel)).map_err(ImportError::UnsafePath)
+    };
+
+    let config: ImageConfigFile = read_json(&in_tar(&manifest.config)?)?;
+
+    Ok(Image {
+        layers: manifest.layers.iter().map(|layer| in_tar(layer)).collect::<Result<_, _>>()?,
+        config: config.config
+    })
+}
+
+fn read_oci(root: &Path) -> Result<Image, ImportError> {
+    let mut index: OciIndex = read_json(&root.join("index.json"))?;
+
+    // follow nested indexes down to a single manifest, preferring linux/amd64
+    let manifest: OciManifest = loop {
+        let pick = index.manifests.iter().position(|desc| desc.platform.as_ref().is_some_and(|platform| platform.os == "linux" && platform.architecture == "amd64")).unwrap_or(0);
+        let desc = index.manifests.into_iter().nth(pick).ok_or(ImportError::NoImage)?;
+        let path = blob_path(root, &desc.digest)?;
+
+        match desc.media_type.as_str() {
+            "application/vnd.oci.image.index.v1+json" | "application/vnd.docker.distribution.manifest.list.v2+json" => index = read_json(&path)?,
+            _ => break read_json(&path)?
+        }
+    };
+
+    let config: ImageConfigFile = read_json(&blob_path(root, &manifest.config.digest)?)?;
+
+    Ok(Image {
+        layers: manifest.layers.iter().map(|layer| blob_path(root, &layer.digest)).collect::<Result<_, _>>()?,
+        config: config.config
+    })
+}
+
+// plain or gzipped tar, going by the magic bytes rather than the media type since docker save doesn't have one
+fn open_tar(path: &Path) -> Result<Box<dyn Read>, ImportError> {
+    let mut file = File::open(path).map_err(|err| ImportError::OpenLayer(path.to_owned(), err))?;
+    let mut magic = [0u8; 4];
+
+    let len = file.read(&mut magic).map_err(|err| ImportError::OpenLayer(path.to_owned(), err))?;
+
+    file.seek(SeekFrom::Start(0)).map_err(|err| ImportError::OpenLayer(path.to_owned(), err))?;
+
+    match &magic[..len] {
+        [0x1f, 0x8b, ..] => Ok(Box::new(GzDecoder::new(BufReader::new(file)))),
+        [0x28, 0xb5, 0x2f, 0xfd] => Err(ImportError::UnsupportedCompression(path.to_owned())),
+        _ => Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// creates any missing parents of rel under root, refusing to go through symlinks
+fn make_parents(root: &Path, rel: &Path) -> io::Result<()> {
+    let mut path = root.to_owned();
+
+    if let Some(parent) = rel.parent() {
+        for part in parent.components() {
+            path.push(part);
+
+            match fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() => {}
+                Ok(_) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// OCI whiteouts become what overlay expects: .wh.<name> -> 0/0 char device <name>, .wh..wh..opq -> trusted.overlay.opaque=y on the dir
+fn unpack_into(blob: &Path, root: &Path) -> Result<(), ImportError> {
+    let mut archive = tar::Archive::new(open_tar(blob)?);
+
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_unpack_xattrs(true);
+    archive.set_overwrite(true);
+
+    let mut whiteouts: Vec<PathBuf> = Vec::new();
+    let mut opaques: Vec<PathBuf> = Vec::new();
+
+    for entry in archive.entries().map_err(|err| ImportError::UnpackLayer(blob.to_owned(), err))? {
+        let mut entry = entry.map_err(|err| ImportError::UnpackLayer(blob.to_owned(), err))?;
+        let path = entry.path().map_err(|err| ImportError::UnpackLayer(blob.to_owned(), err))?;
+        let path = paths::relative(path.strip_prefix("/").unwrap_or(&path)).map_err(ImportError::UnsafePath)?;
+        let name = path.file_name().map(|name| name.as_bytes()).unwrap_or(b"");
+
+        if name == b".wh..wh..opq" {
+            opaques.push(path.parent().unwrap().to_owned());
+        } else if let Some(hidden) = name.strip_prefix(b".wh.") {
+            whiteouts.push(path.with_file_name(std::ffi::OsStr::from_bytes(hidden)));
+        } else {
+            entry.unpack_in(root).map_err(|err| ImportError::UnpackLayer(blob.to_owned(), err))?;
+        }
+    }
+
+    // done after the real entries; a whiteout only hides lower layers, never something this layer adds
+    for path in whiteouts {
+        let full = root.join(&path);
+
+        if fs::symlink_metadata(&full).is_ok() {
+            continue;
+        }
+
+        make_parents(root, &path).map_err(|err| ImportError::Whiteout(path.clone(), err))?;
+
+        let cs_path = CString::new(full.as_os_str().as_bytes()).unwrap();
+
+        if unsafe { libc::mknod(cs_path.as_ptr(), libc::S_IFCHR, libc::makedev(0, 0)) } != 0 {
+            return Err(ImportError::Whiteout(path, io::Error::last_os_error()));
+        }
+    }
+
+    for path in opaques {
+        make_parents(root, &path.join("_")).map_err(|err| ImportError::Opaque(path.clone(), err))?;
+
+        let cs_path = CString::new(root.join(&path).as_os_str().as_bytes()).unwrap();
+        let cs_name = CString::new("trusted.overlay.opaque").unwrap();
+
+        if unsafe { libc::lsetxattr(cs_path.as_ptr(), cs_name.as_ptr(), b"y".as_ptr() as *const libc::c_void, 1, 0) } != 0 {
+            return Err(ImportError::Opaque(path, io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+fn unpack_layer(blob: &Path) -> Result<Digest, ImportError> {
+    let staging = layers::new_staging().map_err(ImportError::Layer)?;
+
+    match unpack_into(blob, &staging.join("root")) {
+        Ok(()) => layers::commit(&staging).map_err(ImportError::Layer),
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging);
+
+            Err(err)
+        }
+    }
+}
+
+// looks the name up in the topmost layer that has the file
+fn lookup(layers: &[Digest], file: &str, name: &str) -> Option<Vec<String>> {
+    for layer in layers {
+        let path = layer.root().join(file);
+
+        if fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_file()) {
+            let contents = fs::read_to_string(&path).ok()?;
+
+            return contents.lines().map(|line| line.split(':').map(str::to_owned).collect::<Vec<String>>()).find(|fields| fields[0] == name);
+        }
+    }
+
+    None
+}
+
+// image users are "user", "uid", "user:group" or "uid:gid"
+fn resolve_user(spec: &str, layers: &[Digest]) -> Result<User, ImportError> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None)
+    };
+
+    let passwd = lookup(layers, "etc/passwd", user);
+
+    let uid: u32 = match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => passwd.as_ref().and_then(|fields| fields.get(2)?.parse().ok()).ok_or_else(|| ImportError::UnknownUser(user.to_owned()))?
+    };
+
+    let gid: u32 = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup(layers, "etc/group", group).and_then(|fields| fields.get(2)?.parse().ok()).ok_or_else(|| ImportError::UnknownGroup(group.to_owned()))?
+        },
+        None => passwd.as_ref().and_then(|fields| fields.get(3)?.parse().ok()).unwrap_or(0)
+    };
+
+    Ok(User {
+        uid,
+        gid,
+        umask: None,
+        additional_gids: Vec::new()
+    })
+}
+
+pub fn import_image(src: &Path) -> Result<Config, ImportError> {
+    // layouts are read in place, tarballs get unpacked somewhere scratch first
+    let scratch = if src.is_dir() {
+        None
+    } else {
+        let scratch = layers::new_staging().map_err(ImportError::Layer)?;
+
+        if let Err(err) = File::open(src).and_then(|file| tar::Archive::new(file).unpack(scratch.join("root"))) {
+            let _ = fs::remove_dir_all(&scratch);
+
+            return Err(ImportError::Unpack(err));
+        }
+
+        Some(scratch)
+    };
+
+    let result = (|| {
+        let root = match &scratch {
+            Some(scratch) => scratch.join("root"),
+            None => src.to_owned()
+        };
+
+        let image = if root.join("manifest.json").is_file() {
+            read_docker(&root)?
+        } else if root.join("index.json").is_file() {
+            read_oci(&root)?
+        } else {
+            return Err(ImportError::NoManifest);
+        };
+
+        let mut digests: Vec<Digest> = image.layers.iter().map(|layer| unpack_layer(layer)).collect::<Result<_, _>>()?;
+
+        // images list layers bottom first, overlay wants them topmost first
+        digests.reverse();
+
+        let user = match image.config.user.as_deref() {
+            None | Some("") => None,
+            Some(spec) => Some(resolve_user(spec, &digests)?)
+        };
+
+        Ok(Config {
+            diffs: digests.iter().map(Digest::to_string).collect(),
+            env: image.config.env.unwrap_or_default(),
+            cwd: image.config.working_dir.filter(|cwd| !cwd.is_empty()),
+            user,
+            upper: UpperLimits::default(),
+            staging: Vec::new()
+        })
+    })();
+
+    if let Some(scratch) = scratch {
+        let _ = fs::remove_dir_all(scratch);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    fn append(builder: &mut tar::Builder<File>, path: &str, kind: tar::EntryType, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+
+        header.set_entry_type(kind);
+        header.set_mode(if kind == tar::EntryType::Directory { 0o755 } else { 0o644 });
+        header.set_size(data.len() as u64);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_become_overlay_markers() {
+        let dir = std::env::temp_dir().join(format!("rto-import-{:016x}", rand::random::<u64>()));
+        let blob = dir.join("layer.tar");
+        let root = dir.join("root");
+
+        fs::create_dir_all(&root).unwrap();
+
+        let mut builder = tar::Builder::new(File::create(&blob).unwrap());
+
+        append(&mut builder, "etc/", tar::EntryType::Directory, b"");
+        append(&mut builder, "etc/.wh.gone", tar::EntryType::Regular, b"");
+        // hidden below, then added back further on in the same layer
+        append(&mut builder, "etc/.wh.readded", tar::EntryType::Regular, b"");
+        append(&mut builder, "etc/readded", tar::EntryType::Regular, b"new");
+        append(&mut builder, "var/cache/.wh..wh..opq", tar::EntryType::Regular, b"");
+        builder.into_inner().unwrap();
+
+        let result = unpack_into(&blob, &root);
+
+        let gone = fs::symlink_metadata(root.join("etc/gone"));
+        let readded = fs::read(root.join("etc/readded"));
+        let opaque = layers::opaque_xattr(&root.join("var/cache"));
+        let markers = ["etc", "var/cache"].iter().flat_map(|sub| fs::read_dir(root.join(sub)).into_iter().flatten()).filter(|entry| entry.as_ref().is_ok_and(|entry| entry.file_name().as_bytes().starts_with(b".wh."))).count();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_ok(), "{:?}", result);
+
+        let gone = gone.unwrap();
+
+        assert!(gone.file_type().is_char_device());
+        assert_eq!(gone.rdev(), libc::makedev(0, 0));
+        assert_eq!(readded.unwrap(), b"new");
+        assert_eq!(opaque, b"y");
+        assert_eq!(markers, 0);
+    }
+}
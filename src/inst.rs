@@ -1,73 +1,520 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::{fs, io, thread};
 use serde_json::Value;
 use std::io::Cursor;
 
 use crate::io_bin::InputStream;
 use crate::{Config, Mode};
-use crate::container::Container;
+use crate::gc;
+use crate::container::{Container, ContainerInitError, CreateContainerError, DestroyError, Outcome, RunError, RuncMode, StdinError};
+use crate::lifecycle::{Emitter, IllegalTransition, State};
 use crate::BASE_OCI_CONFIG;
+use crate::layers::{Digest, LayerError};
+use crate::squash;
+use crate::staging::{self, Directive, Inherited, SpawnConfig, Stage, StagingError, StreamClosed, Values};
+
+const MAX_PARALLEL_CREATES: usize = 8;
 
 struct Inst {
-    id: String,
-    config: Config,
+    id: usize,
+    config: Arc<Config>, // kept as it was at init
     mode: Mode,
-    conts: Vec<Container>
+    emitter: Emitter,
+    state: Mutex<State>,
+    layers: Vec<PathBuf>, // lowerdirs, kept for forks
+    conts: Vec<Container>, // one per case, or just the base if staging forks
+    forks: OnceLock<Vec<Container>>, // one per case, once the base has forked
+    spawned: Mutex<Vec<Arc<Container>>>, // by staging, in the order they were made
+    next_id: AtomicUsize // for the next spawned container, after the cases' and any forks'
 }
 
+#[derive(Clone)]
 pub struct InstFront {
-    inner: Arc<Mutex<Inst>>
+    inner: Arc<Inst>
 }
 
-fn oci_config_from_config(config: &Config, inst_id: &str, id: &str) -> String {
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum InitError {
+    Layer(LayerError),
+    CreateInstDir(io::Error),
+    RecordLayers(io::Error),
+    CreateContainers(Vec<(usize, ContainerInitError)>)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum StartError {
+    State(IllegalTransition),
+    Container(usize, RunError),
+    Fork(Vec<(usize, ContainerInitError)>),
+    Stopped, // while it was making containers
+    BadInputs
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum InputError {
+    NotRunning(State),
+    UnknownContainer(usize),
+    UnknownStream(String),
+    Stdin(StdinError),
+    Stream(StreamClosed)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum StopError {
+    State(IllegalTransition),
+    Destroy(Vec<(usize, DestroyError)>)
+}
+
+// KEY=value vars over env, replacing any with the same key
+pub fn merge_env(env: &mut Vec<String>, vars: &[String]) {
+    for var in vars {
+        let key = var.split('=').next().unwrap();
+        
+        env.retain(|existing| existing.split('=').next().unwrap() != key);
+        env.push(var.clone());
+    }
+}
+
+pub fn oci_spec_from_config(config: &Config, inst_id: &str, id: &str) -> HashMap<String, Value> {
     let mut oci_config: HashMap<String, Value> = serde_json::from_str(BASE_OCI_CONFIG).unwrap(); // stupid rust won't let me do this at compile time >:|
     
     oci_config.insert("hostname".to_owned(), Value::String([oci_config.get("hostname").unwrap().as_str().unwrap(), "-", inst_id, "-", id].concat())); // hostname can be assumed to be a string that exists
+    
+    if let Some(Value::Object(process)) = oci_config.get_mut("process") {
+        let mut env: Vec<String> = match process.get("env") {
+            Some(Value::Array(env)) => env.iter().filter_map(|var| var.as_str().map(str::to_owned)).collect(),
+            _ => Vec::new()
+        };
+        
+        merge_env(&mut env, &config.env);
+        
+        process.insert("env".to_owned(), Value::from(env));
+        
+        if let Some(cwd) = &config.cwd {
+            process.insert("cwd".to_owned(), Value::from(cwd.as_str()));
+        }
+        
+        if let Some(user) = &config.user {
+            process.insert("user".to_owned(), user.to_oci());
+        }
+    }
 
     // make changes based on config?
     //   - namespaces
     //   - mounts
     //   - resources?
     
-    serde_json::to_string(&oci_config).unwrap()
+    oci_config
 }
 
-impl InstFront {
-    pub fn init(id: String, config: Config, mode: Mode) -> Result<InstFront, ()> { // TODO: errtype
-        let cases = match inner.mode {
-            Mode::SingleCase | Mode::Tty => 1,
-            Mode::MultiCase(cases) => cases
-        };
+fn oci_config_from_config(config: &Config, inst_id: &str, id: &str) -> String {
+    serde_json::to_string(&oci_spec_from_config(config, inst_id, id)).unwrap()
+}
 
-        let conts: Vec<Container> = Vec::with_capacity(cases);
+fn cases(mode: &Mode) -> usize {
+    match mode {
+        Mode::SingleCase | Mode::Tty => 1,
+        Mode::MultiCase(cases) => *cases
+    }
+}
 
-        for cont_id in 0..cases {
-            conts.push(Container::init(id.clone(), cont_id.to_string(), &config.diffs, oci_config_from_config(&config, &id, &cont_id.to_string())).map_err(|_| ())?);
+// creates a container for each id, at most MAX_PARALLEL_CREATES at a time; either every one gets made or none do
+fn create_containers(emitter: &Emitter, config: &Config, runc_mode: RuncMode, layers: &[PathBuf], ids: Range<usize>) -> Result<Vec<Container>, Vec<(usize, ContainerInitError)>> {
+    let cases = ids.len();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<Container, ContainerInitError>>>> = Mutex::new((0..cases).map(|_| None).collect());
+    
+    thread::scope(|scope| {
+        for _ in 0..MAX_PARALLEL_CREATES.min(cases) {
+            scope.spawn(|| loop {
+                // don't bother starting new cases once one has failed, they'd just be rolled back
+                if failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                
+                if index >= cases {
+                    break;
+                }
+                
+                let cont_id = ids.start + index;
+                
+                let result = Container::init(emitter.clone(), cont_id, runc_mode, layers, &config.upper, oci_config_from_config(config, &emitter.inst_id().to_string(), &cont_id.to_string()));
+                
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    
+    let mut conts: Vec<(usize, Container)> = Vec::with_capacity(cases);
+    let mut errs: Vec<(usize, ContainerInitError)> = Vec::new();
+    
+    for (cont_id, result) in ids.zip(results.into_inner().unwrap()) {
+        match result {
+            Some(Ok(cont)) => conts.push((cont_id, cont)),
+            Some(Err(err)) => errs.push((cont_id, err)),
+            None => {} // never attempted
+        }
+    }
+    
+    if errs.is_empty() {
+        Ok(conts.into_iter().map(|(_, cont)| cont).collect())
+    } else {
+        for (cont_id, cont) in conts {
+            if let Err(DestroyError::Cleanup(cleanup)) = cont.destroy() {
+                errs.push((cont_id, ContainerInitError {
+                    cause: CreateContainerError::RolledBack,
+                    cleanup
+                }));
+            }
         }
+        
+        Err(errs)
+    }
+}
+
+fn read_values(cursor: &mut Cursor<&[u8]>) -> Result<Values, ()> {
+    let count = cursor.input_size()?;
+    let mut values = Values::new();
+    
+    for _ in 0..count {
+        let name = String::from_utf8(cursor.input_string()?).map_err(|_| ())?;
+        
+        values.insert(name, cursor.input_string()?);
+    }
+    
+    Ok(values)
+}
 
+impl Inst {
+    // for containers made partway through a start: kept only if a stop hasn't got in first, since it would never see them
+    // under the state lock, so a stop either comes after and finds them or came before and they're not kept
+    fn adopt(&self, keep: impl FnOnce()) -> bool {
+        let state = self.state.lock().unwrap();
+        
+        if *state == State::Running {
+            keep();
+        }
+        
+        *state == State::Running
+    }
+}
+
+impl staging::Spawn for Inst {
+    // a spawned container is detached and staged like the rest, just on its own layers; whatever it doesn't set comes from the config
+    fn spawn(&self, diffs: &[String], config: &SpawnConfig) -> Result<Arc<Container>, StagingError> {
+        let digests: Vec<Digest> = diffs.iter().map(|diff| Digest::parse(diff)).collect::<Result<_, _>>().map_err(StagingError::SpawnLayer)?;
+        let layers = squash::lowerdirs(&digests).map_err(StagingError::SpawnLayer)?;
+        let mut env = self.config.env.clone();
+        
+        merge_env(&mut env, &config.env);
+        
+        let spawn_config = Config {
+            diffs: diffs.to_vec(),
+            env,
+            cwd: config.cwd.clone().or_else(|| self.config.cwd.clone()),
+            user: config.user.clone().or_else(|| self.config.user.clone()),
+            upper: config.upper.unwrap_or(self.config.upper),
+            staging: Vec::new()
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let oci_config = oci_config_from_config(&spawn_config, &self.id.to_string(), &id.to_string());
+        let cont = Arc::new(Container::init(self.emitter.clone(), id, RuncMode::Detached, &layers, &spawn_config.upper, oci_config).map_err(StagingError::SpawnInit)?);
+        
+        if !self.adopt(|| self.spawned.lock().unwrap().push(cont.clone())) {
+            let _ = cont.destroy();
+            
+            return Err(StagingError::Stopped);
+        }
+        
+        Ok(cont)
+    }
+}
+
+impl InstFront {
+    pub fn init(emitter: Emitter, config: Arc<Config>, mode: Mode) -> Result<InstFront, InitError> {
+        let created = Self::create(&emitter, &config, &mode);
+        
+        emitter.inst(if created.is_ok() { State::Created } else { State::Failed });
+        
+        let (layers, conts) = created?;
+        let next_id = if staging::split_fork(&config.staging).is_some() { 1 + cases(&mode) } else { conts.len() };
+        
         Ok(Self {
-            inner: Arc::new(Mutex::new(Inst {
-                id,
+            inner: Arc::new(Inst {
+                id: emitter.inst_id(),
                 config,
                 mode,
-                conts
-            }))
+                emitter,
+                state: Mutex::new(State::Created),
+                layers,
+                conts,
+                forks: OnceLock::new(),
+                spawned: Mutex::new(Vec::new()),
+                next_id: AtomicUsize::new(next_id)
+            })
         })
     }
+    
+    fn create(emitter: &Emitter, config: &Config, mode: &Mode) -> Result<(Vec<PathBuf>, Vec<Container>), InitError> {
+        let id = emitter.inst_id();
+        
+        // a fork makes the cases' containers itself, once the base is done
+        let conts = if staging::split_fork(&config.staging).is_some() { 1 } else { cases(mode) };
+        
+        // a tty session lasts as long as the client keeps it going, so it gets runc in the foreground with stdin left open
+        // unless it's staged, in which case the container's own process never runs and everything is exec'd
+        let runc_mode = match mode {
+            Mode::Tty if config.staging.is_empty() => RuncMode::Foreground,
+            Mode::SingleCase | Mode::MultiCase(_) | Mode::Tty => RuncMode::Detached
+        };
+        
+        let digests: Vec<Digest> = config.diffs.iter().map(|diff| Digest::parse(diff)).collect::<Result<_, _>>().map_err(InitError::Layer)?;
+        let spawn_digests: Vec<Vec<Digest>> = staging::spawn_stacks(&config.staging).into_iter().map(|diffs| diffs.iter().map(|diff| Digest::parse(diff)).collect()).collect::<Result<_, _>>().map_err(InitError::Layer)?;
+        
+        fs::create_dir(format!("/rto/conts/{}", id)).map_err(InitError::CreateInstDir)?;
+        
+        // recorded before the layers are even resolved, so the layer gc never sweeps something this instance is about to mount
+        // that includes whatever staging might spawn, which gets resolved much later
+        if let Err(err) = fs::write(format!("/rto/conts/{}/layers", id), gc::layers_record([&digests].into_iter().chain(&spawn_digests))) {
+            let _ = fs::remove_dir_all(format!("/rto/conts/{}", id));
+            
+            return Err(InitError::RecordLayers(err));
+        }
+        
+        // checked once here rather than per container
+        let layers: Vec<PathBuf> = match squash::lowerdirs(&digests) {
+            Ok(layers) => layers,
+            Err(err) => {
+                let _ = fs::remove_dir_all(format!("/rto/conts/{}", id));
+                
+                return Err(InitError::Layer(err));
+            }
+        };
 
-    pub fn start(&self, inputs: &[u8]) {
-        let inner = self.inner.lock().unwrap();
+        let conts = create_containers(emitter, config, runc_mode, &layers, 0..conts).map_err(|errs| {
+            // not remove_dir_all, it would happily walk into anything a failed rollback left mounted
+            let _ = fs::remove_file(format!("/rto/conts/{}/layers", id));
+            let _ = fs::remove_dir(format!("/rto/conts/{}", id));
+            
+            InitError::CreateContainers(errs)
+        })?;
+        
+        Ok((layers, conts))
+    }
+    
+    pub fn state(&self) -> State {
+        *self.inner.state.lock().unwrap()
+    }
+    
+    fn transition(&self, to: State) -> Result<(), IllegalTransition> {
+        let mut state = self.inner.state.lock().unwrap();
+        
+        *state = state.check(to)?;
+        self.inner.emitter.inst(to);
+        
+        Ok(())
+    }
 
-        match inner.mode {
-            Mode::SingleCase | Mode::Tty => inner.conts[0].start(inputs),
-            Mode::MultiCase(_) => {
-                let mut cursor = Cursor::new(inputs);
-                let common = cursor.input_string().unwrap();
+    // one outcome per case; only once, from created
+    // inputs are named values, <count: int> then <name: bytestring> <value: bytestring> for each; a multi-case instance gets one set common to every case, then one per case that overrides it by name
+    pub fn start(&self, inputs: &[u8]) -> Result<Vec<Outcome>, StartError> {
+        self.transition(State::Starting).map_err(StartError::State)?;
+        
+        let inner = &self.inner;
+        let mut cursor = Cursor::new(inputs);
+        
+        let values: Result<(Values, Vec<Values>), ()> = match inner.mode {
+            Mode::SingleCase | Mode::Tty => read_values(&mut cursor).map(|values| (values.clone(), vec![values])),
+            Mode::MultiCase(cases) => read_values(&mut cursor).and_then(|common| (0..cases).map(|_| {
+                let mut values = common.clone();
+                
+                values.extend(read_values(&mut cursor)?);
+                
+                Ok(values)
+            }).collect::<Result<Vec<Values>, ()>>().map(|values| (common, values)))
+        };
+        
+        let (common, values) = match values {
+            Ok(values) if cursor.position() == inputs.len() as u64 => values,
+            _ => {
+                self.fail_start();
 
-                for cont in inner.conts {
-                    cont.start(&[common, cursor.input_string().unwrap()].concat());
+                return Err(StartError::BadInputs);
+            }
+        };
+        
+        self.transition(State::Running).map_err(StartError::State)?;
+        
+        match self.run(&common, &values) {
+            Ok(outcomes) => {
+                self.transition(State::Exited).map_err(StartError::State)?;
+                
+                Ok(outcomes)
+            }
+            Err(err) => {
+                self.fail_start();
+                
+                Err(err)
+            }
+        }
+    }
+    
+    // a start that went wrong is the instance's failure, unless a stop has already taken over
+    fn fail_start(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        
+        if matches!(*state, State::Starting | State::Running) {
+            *state = State::Failed;
+            self.inner.emitter.inst(State::Failed);
+        }
+    }
+    
+    fn run(&self, common: &Values, values: &[Values]) -> Result<Vec<Outcome>, StartError> {
+        let inner = &self.inner;
+        
+        Ok(match staging::split_fork(&inner.config.staging) {
+            Some((shared, per_case, simul)) => self.fork(shared, per_case, simul, common, values)?,
+            None => {
+                let mut outcomes: Vec<Outcome> = Vec::with_capacity(inner.conts.len());
+                
+                for (cont_id, (cont, values)) in inner.conts.iter().zip(values).enumerate() {
+                    // without staging, the only value there's any use for is the process's stdin
+                    let outcome = if inner.config.staging.is_empty() {
+                        cont.start(values.get("stdin").map_or(&[][..], |stdin| &stdin[..]))
+                    } else {
+                        cont.stage(|cont| {
+                            let mut stage = Stage::new(cont, values, &**inner);
+                            
+                            stage.run(&inner.config.staging)?;
+                            
+                            Ok(stage.failed())
+                        })
+                    };
+                    
+                    outcomes.push(outcome.map_err(|err| StartError::Container(cont_id, err))?);
                 }
+                
+                outcomes
+            }
+        })
+    }
+    
+    // the shared directives run once in the base with the common values, then each case's container is made on top of what the base wrote
+    // the base has exited by then, so its upper holds still under the forks
+    fn fork(&self, shared: &[Directive], per_case: &[Directive], simul: bool, common: &Values, values: &[Values]) -> Result<Vec<Outcome>, StartError> {
+        let inner = &self.inner;
+        let mut inherited = Inherited::default();
+        
+        // the cases go ahead on a base that failed or filled up, so they can say so, but none of them gets to look better than it
+        let base = inner.conts[0].stage(|base| {
+            let mut stage = Stage::new(base, common, &**inner);
+            let result = stage.run(shared);
+            
+            inherited = stage.inherited();
+            
+            result.map(|()| stage.failed())
+        }).map_err(|err| StartError::Container(0, err))?;
+        
+        let layers: Vec<PathBuf> = [inner.conts[0].upper()].into_iter().chain(inner.layers.iter().cloned()).collect();
+        let forks = create_containers(&inner.emitter, &inner.config, RuncMode::Detached, &layers, 1..values.len() + 1).map_err(StartError::Fork)?;
+        
+        let mut forks = Some(forks);
+        
+        // start only ever gets this far once, so they're always the ones set
+        if !inner.adopt(|| drop(inner.forks.set(forks.take().unwrap()))) {
+            for cont in forks.into_iter().flatten() {
+                let _ = cont.destroy();
             }
+            
+            return Err(StartError::Stopped);
+        }
+        
+        let forks = inner.forks.get().unwrap();
+        
+        let run_case = |(case, (cont, values)): (usize, (&Container, &Values))| cont.stage(|cont| {
+            let mut stage = Stage::forked(cont, values, &**inner, &inherited);
+            
+            stage.run(per_case)?;
+            
+            Ok(stage.failed())
+        }).map(|outcome| outcome.worst(base)).map_err(|err| StartError::Container(case + 1, err));
+        
+        if simul {
+            thread::scope(|scope| {
+                let handles: Vec<_> = forks.iter().zip(values).enumerate().map(|case| scope.spawn(move || run_case(case))).collect();
+                
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            })
+        } else {
+            forks.iter().zip(values).enumerate().map(run_case).collect()
         }
     }
-}
\ No newline at end of file
+    
+    // container n is the nth case's, or the base then each case's if staging forks
+    fn cont(&self, cont_id: usize) -> Option<&Container> {
+        self.inner.conts.iter().chain(self.inner.forks.get().into_iter().flatten()).nth(cont_id)
+    }
+    
+    // one staging spawned, by its id rather than its position since spawns and forks can come in either order
+    fn spawned(&self, cont_id: usize) -> Option<Arc<Container>> {
+        self.inner.spawned.lock().unwrap().iter().find(|cont| cont.id() == cont_id).cloned()
+    }
+    
+    // a staged container takes input on any stream its directives read; otherwise there's only its process's stdin
+    pub fn input(&self, cont_id: usize, stream: &str, data: &[u8]) -> Result<(), InputError> {
+        let state = self.state();
+
+        if state != State::Running {
+            return Err(InputError::NotRunning(state));
+        }
+
+        let spawned = self.spawned(cont_id);
+        let cont = self.cont(cont_id).or(spawned.as_deref()).ok_or(InputError::UnknownContainer(cont_id))?;
+
+        if !self.inner.config.staging.is_empty() {
+            cont.streams().push(stream, data).map_err(InputError::Stream)
+        } else if stream == "stdin" {
+            cont.write_stdin(data).map_err(InputError::Stdin)
+        } else {
+            Err(InputError::UnknownStream(stream.to_owned()))
+        }
+    }
+    
+    // tears down every container, whatever they're doing; the instance is gone afterwards unless something couldn't be cleaned up
+    pub fn stop(&self) -> Result<(), StopError> {
+        self.transition(State::Stopping).map_err(StopError::State)?;
+        
+        // spawned ones first, they hold the pipes the others might be stuck writing to; then forks, their roots are stacked on the base's upper
+        let spawned = self.inner.spawned.lock().unwrap().clone();
+        let spawned = spawned.iter().map(|cont| (cont.id(), &**cont));
+        let forks = self.inner.forks.get().into_iter().flatten().enumerate().map(|(case, cont)| (self.inner.conts.len() + case, cont));
+        let errs: Vec<(usize, DestroyError)> = spawned.chain(forks).chain(self.inner.conts.iter().enumerate()).filter_map(|(cont_id, cont)| cont.destroy().err().map(|err| (cont_id, err))).collect();
+        
+        if errs.is_empty() {
+            let _ = fs::remove_file(format!("/rto/conts/{}/layers", self.inner.id));
+            let _ = fs::remove_dir(format!("/rto/conts/{}", self.inner.id));
+            
+            self.transition(State::Destroyed).map_err(StopError::State)
+        } else {
+            // the leftovers are marked broken, and the sweeper takes the rest once the instance is dropped
+            let _ = self.transition(State::Failed);
+            
+            Err(StopError::Destroy(errs))
+        }
+    }
+}
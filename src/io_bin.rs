@@ -1,11 +1,5 @@
 use std::io::{Read, Write};
 
-pub trait LockedInputStream {
-    type Lock: InputStream;
-    
-    fn lock(&mut self) -> Self::Lock;
-}
-
 pub trait InputStream {
     fn input_byte(&mut self) -> Result<u8, ()>;
     fn input_bytes_buf(&mut self, buf: &mut [u8]) -> Result<(), ()>;
@@ -45,27 +39,22 @@ impl<T> InputStream for T where T: Read {
     }
 
     fn input_bytes_buf(&mut self, buf: &mut [u8]) -> Result<(), ()> {
-        self.read_exact(&mut buf).map_err(|_| ())
+        self.read_exact(buf).map_err(|_| ())
     }
 }
 
-pub trait LockedOutputStream {
-    type Lock: OutputStream;
-    
-    fn lock(&mut self) -> Self::Lock;
-}
-
 pub trait OutputStream {
-    fn output_byte(&mut self, byte: u8) -> Result<(), ()>;
     fn output_bytes(&mut self, bytes: &[u8]) -> Result<(), ()>;
     
+    // most significant group first, every byte but the last with the high bit set, as input_size reads it
     fn output_size(&mut self, mut size: usize) -> Result<(), ()> {
-        let bytes: Vec<u8> = Vec::with_capacity((usize::BITS >> 3) as usize);
+        let mut bytes: Vec<u8> = Vec::with_capacity((usize::BITS >> 3) as usize);
         
-        bytes.push((0x80 | size % 128) as u8);
+        bytes.push((size % 128) as u8);
+        size >>= 7;
         
         while size != 0 {
-            bytes.push((size % 128) as u8);
+            bytes.push((0x80 | (size % 128)) as u8);
             
             size >>= 7;
         }
@@ -86,11 +75,30 @@ pub trait OutputStream {
 }
 
 impl<T> OutputStream for T where T: Write {
-    fn output_byte(&mut self, byte: u8) -> Result<(), ()> {
-        self.write_all(&[byte]).map_err(|_| ())
-    }
-
     fn output_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
         self.write_all(bytes).map_err(|_| ())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn sizes_round_trip() {
+        for size in [0, 1, 127, 128, 300, 16383, 16384, usize::MAX] {
+            let mut bytes: Vec<u8> = Vec::new();
+
+            bytes.output_size(size).unwrap();
+
+            assert_eq!(Cursor::new(&bytes).input_size().unwrap(), size);
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+
+        bytes.output_size(300).unwrap();
+
+        assert_eq!(bytes, [0x82, 0x2c]);
+    }
+}
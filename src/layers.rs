@@ -0,0 +1,266 @@
+use std::fs::{self, File};
+use std::io::{self, Error};
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::process::{Command, Stdio};
+use sha2::{Digest as _, Sha256};
+
+use crate::paths::{self, PathError};
+
+// layers live at <LAYERS_ROOT>/sha256/<hex>/root, and <hex>/sealed holds the digest once the contents have been checked against it
+pub const LAYERS_ROOT: &str = "/rto/imgs/layers";
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum LayerError {
+    BadDigest(String),
+    Missing(Digest),
+    Mismatch {
+        expected: Digest,
+        found: Digest
+    },
+    Hash(Error),
+    CreateTmpDir(Error),
+    CopyCommand(Error),
+    Copy(Option<i32>),
+    Seal(Error),
+    Commit(Error),
+    List(Error),
+    Path(PathError),
+    Squash(PathBuf, Error)
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(String); // lowercase hex sha256
+
+impl Digest {
+    pub fn parse(string: &str) -> Result<Digest, LayerError> {
+        match string.strip_prefix("sha256:") {
+            Some(hex) if hex.len() == 64 && hex.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) => Ok(Digest(hex.to_owned())),
+            _ => Err(LayerError::BadDigest(string.to_owned()))
+        }
+    }
+
+    pub fn hex(&self) -> &str {
+        &self.0
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        PathBuf::from(format!("{}/sha256/{}", LAYERS_ROOT, self.0))
+    }
+
+    // what gets used as an overlay lowerdir
+    pub fn root(&self) -> PathBuf {
+        self.dir().join("root")
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sha256:{}", self.0)
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn opaque_xattr(path: &Path) -> Vec<u8> {
+    let cs_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let cs_name = CString::new("trusted.overlay.opaque").unwrap();
+    let mut value = [0u8; 16];
+
+    match unsafe { libc::lgetxattr(cs_path.as_ptr(), cs_name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) } {
+        len if len > 0 => value[..len as usize].to_vec(),
+        _ => Vec::new()
+    }
+}
+
+// covers everything overlay cares about: names, types, modes, owners, contents, link targets, device numbers (whiteouts) and opaque markers
+fn hash_entry(hasher: &mut Sha256, path: &Path, rel: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    let file_type = meta.file_type();
+
+    hasher.update(rel.as_os_str().as_bytes());
+    hasher.update([0]);
+    hasher.update(meta.mode().to_be_bytes());
+    hasher.update(meta.uid().to_be_bytes());
+    hasher.update(meta.gid().to_be_bytes());
+
+    if file_type.is_dir() {
+        let opaque = opaque_xattr(path);
+
+        hasher.update((opaque.len() as u64).to_be_bytes());
+        hasher.update(&opaque);
+
+        let mut names: Vec<OsString> = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.file_name())).collect::<io::Result<_>>()?;
+
+        names.sort();
+
+        hasher.update((names.len() as u64).to_be_bytes());
+
+        for name in names {
+            hash_entry(hasher, &path.join(&name), &rel.join(&name))?;
+        }
+    } else if file_type.is_file() {
+        hasher.update(meta.len().to_be_bytes());
+
+        io::copy(&mut File::open(path)?, hasher)?;
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+
+        hasher.update((target.as_os_str().len() as u64).to_be_bytes());
+        hasher.update(target.as_os_str().as_bytes());
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        hasher.update(meta.rdev().to_be_bytes());
+    }
+
+    Ok(())
+}
+
+pub fn hash_tree(root: &Path) -> Result<Digest, LayerError> {
+    let mut hasher = Sha256::new();
+
+    hash_entry(&mut hasher, root, Path::new("")).map_err(LayerError::Hash)?;
+
+    Ok(Digest(hex(&hasher.finalize())))
+}
+
+fn is_sealed(digest: &Digest) -> bool {
+    match fs::read_to_string(digest.dir().join("sealed")) {
+        Ok(sealed) => sealed.trim() == digest.to_string(),
+        Err(_) => false
+    }
+}
+
+// rehashes the layer's contents and seals it if they match
+pub fn verify(digest: &Digest) -> Result<(), LayerError> {
+    let root = digest.root();
+
+    if !root.is_dir() {
+        return Err(LayerError::Missing(digest.clone()));
+    }
+
+    let found = hash_tree(&root)?;
+
+    if &found != digest {
+        return Err(LayerError::Mismatch {
+            expected: digest.clone(),
+            found
+        });
+    }
+
+    fs::write(digest.dir().join("sealed"), digest.to_string()).map_err(LayerError::Seal)
+}
+
+// path to use as a lowerdir, only handed out for sealed or freshly verified layers
+pub fn resolve(digest: &Digest) -> Result<PathBuf, LayerError> {
+    if !is_sealed(digest) {
+        verify(digest)?;
+    }
+
+    // a layer dir swapped for a symlink mustn't be able to point the overlay somewhere else on the host
+    paths::resolve_under(Path::new(LAYERS_ROOT), &Path::new("sha256").join(digest.hex()).join("root")).map_err(LayerError::Path)
+}
+
+// a fresh directory to build a layer in; the contents go in <staging>/root
+pub fn new_staging() -> Result<PathBuf, LayerError> {
+    let staging = PathBuf::from(format!("{}/tmp/{:016x}", LAYERS_ROOT, rand::random::<u64>()));
+
+    fs::create_dir_all(staging.join("root")).map_err(LayerError::CreateTmpDir)?;
+
+    Ok(staging)
+}
+
+// moves a staged layer into the store under its digest; the staging dir is gone afterwards either way
+pub fn commit(staging: &Path) -> Result<Digest, LayerError> {
+    let result = (|| {
+        let digest = hash_tree(&staging.join("root"))?;
+
+        if is_sealed(&digest) {
+            return Ok(digest);
+        }
+
+        fs::write(staging.join("sealed"), digest.to_string()).map_err(LayerError::Seal)?;
+        fs::create_dir_all(format!("{}/sha256", LAYERS_ROOT)).map_err(LayerError::Commit)?;
+
+        // an unsealed leftover under the same digest gets replaced
+        let _ = fs::remove_dir_all(digest.dir());
+
+        fs::rename(staging, digest.dir()).map_err(LayerError::Commit)?;
+
+        Ok(digest)
+    })();
+
+    let _ = fs::remove_dir_all(staging);
+
+    result
+}
+
+// copies a directory tree into the store, returns the digest it ended up under
+pub fn import(src: &Path) -> Result<Digest, LayerError> {
+    let staging = new_staging()?;
+
+    // hash the copy, not the source, so the source changing mid-import can't produce a mislabeled layer
+    match Command::new("/bin/cp").arg("-a").arg("--no-target-directory").arg(src).arg(staging.join("root")).stdin(Stdio::null()).stdout(Stdio::null()).status() {
+        Ok(status) if status.success() => commit(&staging),
+        result => {
+            let _ = fs::remove_dir_all(&staging);
+
+            Err(match result {
+                Ok(status) => LayerError::Copy(status.code()),
+                Err(err) => LayerError::CopyCommand(err)
+            })
+        }
+    }
+}
+
+// every layer in the store and whether it's sealed
+pub fn list() -> Result<Vec<(Digest, bool)>, LayerError> {
+    let mut layers: Vec<(Digest, bool)> = Vec::new();
+
+    let entries = match fs::read_dir(format!("{}/sha256", LAYERS_ROOT)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(layers),
+        Err(err) => return Err(LayerError::List(err))
+    };
+
+    for entry in entries {
+        let name = entry.map_err(LayerError::List)?.file_name();
+
+        if let Ok(digest) = Digest::parse(&format!("sha256:{}", name.to_string_lossy())) {
+            let sealed = is_sealed(&digest);
+
+            layers.push((digest, sealed));
+        }
+    }
+
+    layers.sort();
+
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let hex = "2aa99ae30bf4d6a2e16ec1b7a82de62a433ab3a5e40a16cd579b5e427bc4156c";
+
+        assert_eq!(Digest::parse(&format!("sha256:{}", hex)).unwrap().hex(), hex);
+
+        for bad in [hex.to_owned(), format!("sha512:{}", hex), format!("sha256:{}", hex.to_uppercase()), format!("sha256:{}0", hex), format!("sha256:../../{}", &hex[6..]), format!("sha256:{},upperdir=/", &hex[..52])] {
+            assert!(matches!(Digest::parse(&bad), Err(LayerError::BadDigest(_))), "{:?} accepted", bad);
+        }
+    }
+}
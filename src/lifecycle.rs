@@ -0,0 +1,212 @@
+use std::sync::mpsc::Sender;
+use std::os::unix::process::ExitStatusExt;
+
+use crate::io_bin::OutputStream;
+
+// the states an instance and each of its containers go through, and the frames that tell the client about them:
+//   0x83 <inst_id: 8 bytes be> <subject> <state: byte>                          every transition
+//   0x84 <inst_id: 8 bytes be> <subject> <stream: bytestring> <data: bytestring>  output on a named stream, stdout and stderr for the container's own process; empty data closes it
+//   0x85 <inst_id: 8 bytes be> <subject> <status>                             the container's process finished
+//   0x88 <inst_id: 8 bytes be> <subject> <exec: int> <status>                 a process exec'd into the container finished
+//   0x89 <inst_id: 8 bytes be> <subject> <outcomes: bytestring>               a simul's branches all finished, one outcome each: 0 succeeded, 1 failed, 2 cancelled, 3 errored
+// where status is 0x00 <code: 4 bytes be> or 0x01 <signal: byte>
+// where subject is an int, 0 for the instance and n + 1 for container n
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Created,
+    Starting,
+    Running,
+    Exited,
+    Stopping,
+    Destroyed,
+    Failed
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub from: State,
+    pub to: State
+}
+
+impl State {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            State::Created => 0x00,
+            State::Starting => 0x01,
+            State::Running => 0x02,
+            State::Exited => 0x03,
+            State::Stopping => 0x04,
+            State::Destroyed => 0x05,
+            State::Failed => 0x06
+        }
+    }
+
+    // anything can fail or be stopped until it's gone; otherwise it only moves forward
+    pub fn allows(self, to: State) -> bool {
+        match (self, to) {
+            (State::Destroyed, _) => false,
+            (State::Failed, State::Failed) | (State::Stopping, State::Stopping) => false,
+            (_, State::Failed | State::Stopping) => true,
+            (State::Created, State::Starting) | (State::Starting, State::Running) | (State::Running, State::Exited) | (State::Stopping, State::Destroyed) => true,
+            _ => false
+        }
+    }
+
+    pub fn check(self, to: State) -> Result<State, IllegalTransition> {
+        if self.allows(to) {
+            Ok(to)
+        } else {
+            Err(IllegalTransition {
+                from: self,
+                to
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Code(i32),
+    Signal(i32)
+}
+
+impl ExitStatus {
+    // from a wait status
+    pub fn from_raw(status: libc::c_int) -> Self {
+        if libc::WIFSIGNALED(status) {
+            ExitStatus::Signal(libc::WTERMSIG(status))
+        } else {
+            ExitStatus::Code(libc::WEXITSTATUS(status))
+        }
+    }
+
+    // of a runc that ran the process in the foreground: runc passes on the process's exit code, and reports a signal as 128 + the signal
+    pub fn from_runc(status: std::process::ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => ExitStatus::Code(code),
+            (None, signal) => ExitStatus::Signal(signal.unwrap_or(0))
+        }
+    }
+
+    fn push_to(self, frame: &mut Vec<u8>) {
+        match self {
+            ExitStatus::Code(code) => {
+                frame.push(0x00);
+                frame.extend_from_slice(&code.to_be_bytes());
+            }
+            ExitStatus::Signal(signal) => {
+                frame.push(0x01);
+                frame.push(signal as u8);
+            }
+        }
+    }
+}
+
+// frames bound for the client, written out in order by main's output thread
+pub type Output = Sender<Vec<u8>>;
+
+#[derive(Clone)]
+pub struct Emitter {
+    output: Output,
+    inst_id: usize
+}
+
+impl Emitter {
+    pub fn new(output: Output, inst_id: usize) -> Self {
+        Self {
+            output,
+            inst_id
+        }
+    }
+
+    pub fn inst_id(&self) -> usize {
+        self.inst_id
+    }
+
+    fn frame(&self, opcode: u8, subject: usize) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![opcode];
+
+        frame.extend_from_slice(&self.inst_id.to_be_bytes());
+        frame.output_size(subject).unwrap();
+
+        frame
+    }
+
+    // the receiver only goes away when the conductor does
+    fn send(&self, frame: Vec<u8>) {
+        let _ = self.output.send(frame);
+    }
+
+    fn emit(&self, subject: usize, state: State) {
+        let mut frame = self.frame(0x83, subject);
+
+        frame.push(state.to_byte());
+
+        self.send(frame);
+    }
+
+    pub fn inst(&self, state: State) {
+        self.emit(0, state);
+    }
+
+    pub fn container(&self, id: usize, state: State) {
+        self.emit(id + 1, state);
+    }
+
+    pub fn output(&self, id: usize, stream: &str, data: &[u8]) {
+        let mut frame = self.frame(0x84, id + 1);
+
+        frame.output_string(stream.as_bytes()).unwrap();
+        frame.output_string(data).unwrap();
+
+        self.send(frame);
+    }
+
+    pub fn exited(&self, id: usize, status: ExitStatus) {
+        let mut frame = self.frame(0x85, id + 1);
+
+        status.push_to(&mut frame);
+
+        self.send(frame);
+    }
+
+    pub fn joined(&self, id: usize, outcomes: &[u8]) {
+        let mut frame = self.frame(0x89, id + 1);
+
+        frame.output_string(outcomes).unwrap();
+
+        self.send(frame);
+    }
+
+    pub fn exec_exited(&self, id: usize, exec_id: usize, status: ExitStatus) {
+        let mut frame = self.frame(0x88, id + 1);
+
+        frame.output_size(exec_id).unwrap();
+        status.push_to(&mut frame);
+
+        self.send(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        assert!(State::Created.allows(State::Starting));
+        assert!(State::Running.allows(State::Exited));
+        assert!(State::Exited.allows(State::Stopping));
+        assert!(State::Failed.allows(State::Stopping));
+        assert!(State::Stopping.allows(State::Destroyed));
+
+        assert!(!State::Running.allows(State::Starting));
+        assert!(!State::Exited.allows(State::Running));
+        assert!(!State::Created.allows(State::Destroyed));
+        assert!(!State::Stopping.allows(State::Stopping));
+        assert!(!State::Destroyed.allows(State::Stopping));
+        assert!(!State::Destroyed.allows(State::Failed));
+    }
+}
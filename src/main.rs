@@ -1,21 +1,32 @@
+use std::{env, process};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::fs::{self, File};
-use std::ffi::CString;
-use std::process::{Command, Stdio};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Read, Write};
 use std::thread;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
 use std::sync::mpsc;
 
 mod io_bin;
 mod inst;
 mod container;
-
-use inst::InstFront as Inst;
-
-pub type Try<T> = Option<T>; // Don't care about the contents of errors
+mod layers;
+mod cli;
+mod image_import;
+mod builder;
+mod registry;
+mod paths;
+mod squash;
+mod gc;
+mod reconcile;
+mod lifecycle;
+mod staging;
+
+use inst::{InstFront as Inst, StopError};
+use registry::{Registry, ReloadReport, SharedRegistry};
+use reconcile::{LiveInsts, ReconcileReport};
+use container::Outcome;
+use lifecycle::Emitter;
 
 const BASE_OCI_CONFIG: &str = include_str!("../base_oci_config.json");
 
@@ -58,98 +69,82 @@ struct OciConfigLinux {
     
 }*/
 
-#[derive(Deserialize)]
+// a fully resolved language config; see registry::RawConfig for what's on disk
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 struct Config {
-    diffs: Vec<String>
+    diffs: Vec<String>, // layer digests, topmost first
+    #[serde(default)]
+    env: Vec<String>, // KEY=value, layered over the base spec's env
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<User>,
+    #[serde(default)]
+    upper: UpperLimits,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    staging: Vec<staging::Directive> // what to do once started, instead of running the container's own process
 }
 
-enum Mode {
-    SingleCase,
-    MultiCase(usize),
-    Tty
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+struct User {
+    uid: u32,
+    gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    umask: Option<u32>, // the base spec's if left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    additional_gids: Vec<u32>
 }
 
-#[derive(Debug)]
-enum CreateContainerError {
-    CreateDir(io::Error),
-    CreateWorkDir(io::Error),
-    CreateTopDir(io::Error),
-    CreateRootDir(io::Error),
-    MountRoot(io::Error),
-    WriteConfig(io::Error),
-    RuncCommand(io::Error),
-    RuncWait(io::Error),
-    RuncCreate(Option<i32>)
-}
+impl User {
+    // the process.user of an oci spec
+    fn to_oci(&self) -> serde_json::Value {
+        let mut user = serde_json::json!({
+            "uid": self.uid,
+            "gid": self.gid,
+            "additionalGids": self.additional_gids
+        });
 
-fn create_container(id: usize, diffs: &[String], config: &str) -> Result<(), CreateContainerError> {
-    fn lowerdir_from_diffs(diffs: &[String]) -> String {
-        let mut lowerdir = String::new();
-        let mut is_first: bool = true;
-        
-        for diff in diffs {
-            if is_first {
-                is_first = false;
-            } else {
-                lowerdir.push(':');
-            }
-            
-            lowerdir += "/rto/imgs/diffs/";
-            lowerdir += diff;
+        if let Some(umask) = self.umask {
+            user["umask"] = serde_json::Value::from(umask);
         }
-        
-        lowerdir
-    }
-    
-    fs::create_dir(format!("/rto/conts/{}", id)).map_err(CreateContainerError::CreateDir)?;
-    
-    fn create_subdirs(id: usize) -> Result<(), CreateContainerError> {
-        fs::create_dir(format!("/rto/conts/{}/work", id)).map_err(CreateContainerError::CreateWorkDir)?;
-        fs::create_dir(format!("/rto/conts/{}/top", id)).map_err(CreateContainerError::CreateTopDir)?;
-        fs::create_dir(format!("/rto/conts/{}/root", id)).map_err(CreateContainerError::CreateRootDir)?;
-        
-        Ok(())
-    }
-    
-    if let Err(err) = create_subdirs(id) {
-        fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
 
-        return Err(err);
-    }
-    
-    let cs_overlay = CString::new("overlay").unwrap();
-    let cs_root = CString::new(format!("/rto/conts/{}/root", id)).unwrap();
-    let cs_options = CString::new(format!("lowerdir={diffs},upperdir=/rto/conts/{id}/top,workdir=/rto/conts/{id}/work,volatile", id = id, diffs = lowerdir_from_diffs(diffs))).unwrap();
-
-    if unsafe { libc::mount(cs_overlay.as_ptr(), cs_root.as_ptr(), cs_overlay.as_ptr(), 0, cs_options.as_ptr() as *const libc::c_void) } != 0 {
-        fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
-        
-        return Err(CreateContainerError::MountRoot(io::Error::last_os_error()));
+        user
     }
-        
-    if let Err(err) = fs::write(format!("/rto/conts/{}/config.json", id), config) {
-        if unsafe { libc::umount(cs_root.as_ptr()) } != 0 { panic!() }; // umount2 instead?
-
-        fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
+}
 
-        // TODO: have a way to mark sticky/broken container attempts instead of panicking, assuming they are clean
+// caps on a container's writable layer, which lives on its own tmpfs
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+struct UpperLimits {
+    #[serde(default = "UpperLimits::default_size")]
+    size: u64, // bytes
+    #[serde(default = "UpperLimits::default_inodes")]
+    inodes: u64
+}
 
-        return Err(CreateContainerError::WriteConfig(err));
+impl UpperLimits {
+    fn default_size() -> u64 {
+        64 << 20
+    }
+    
+    fn default_inodes() -> u64 {
+        16384
     }
+}
 
-    match Command::new("/usr/bin/runc").args(["create", &format!("rto_{}", id)]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().map_err(CreateContainerError::RuncCommand)?.wait().map_err(CreateContainerError::RuncWait)?.code() {
-        Some(0) => Ok(()),
-        code @ (None | Some(_)) => {
-            if unsafe { libc::umount(cs_root.as_ptr()) } != 0 { panic!() };
-            
-            fs::remove_dir(format!("/rto/conts/{}", id)).unwrap();
-            
-            Err(CreateContainerError::RuncCreate(code))
+impl Default for UpperLimits {
+    fn default() -> Self {
+        Self {
+            size: Self::default_size(),
+            inodes: Self::default_inodes()
         }
     }
 }
 
-
+enum Mode {
+    SingleCase,
+    MultiCase(usize),
+    Tty
+}
 
 /*enum InstState {
     Init {
@@ -238,6 +233,82 @@ fn random_inst_id(insts: &HashMap<usize, Inst>) -> usize {
 }
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    
+    if !args.is_empty() {
+        process::exit(cli::run(&args));
+    }
+    
+    // detached containers' processes are orphaned when runc exits; this makes them ours to wait on
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } != 0 {
+        eprintln!("subreaper: {:?}", io::Error::last_os_error());
+    }
+    
+    // that includes the ones that are never started, which nothing else waits on
+    thread::spawn(container::reap_orphans);
+    
+    // without any configs there's nothing an instance could be started from
+    let registry: SharedRegistry = match Registry::load(Path::new(registry::CONFIGS_ROOT)) {
+        Ok(registry) => Arc::new(Mutex::new(Arc::new(registry))),
+        Err(err) => {
+            eprintln!("configs {}: {:?}", registry::CONFIGS_ROOT, err);
+            
+            process::exit(1);
+        }
+    };
+    
+    // bad configs shouldn't take the conductor down, they just can't be used
+    for (lang_id, err) in registry.lock().unwrap().rejected() {
+        eprintln!("config {}: {:?}", lang_id, err);
+    }
+    
+    fn write_reload_report(report: io::Result<ReloadReport>) {
+        match report {
+            Ok(report) => io::stdout().lock().write_all(&[&[0x82u8, 0x00u8], &serde_json::to_vec(&report).unwrap()[..]].concat()).unwrap(),
+            Err(err) => io::stdout().lock().write_all(&[&[0x82u8, 0x01u8], format!("{:?}", err).as_bytes()].concat()).unwrap()
+        }
+        
+        io::stdout().flush().unwrap();
+    }
+    
+    // a failed watch just means reloads have to be asked for
+    if let Err(err) = registry::watch(registry.clone(), PathBuf::from(registry::CONFIGS_ROOT), write_reload_report) {
+        eprintln!("watch {}: {:?}", registry::CONFIGS_ROOT, err);
+    }
+    
+    fn log_sweep(report: io::Result<ReconcileReport>) {
+        match report {
+            Ok(report) => {
+                for runc_id in &report.deleted {
+                    eprintln!("sweep: deleted {}", runc_id);
+                }
+                
+                for mount in &report.unmounted {
+                    eprintln!("sweep: unmounted {}", mount.display());
+                }
+                
+                for dir in &report.removed {
+                    eprintln!("sweep: removed {}", dir.display());
+                }
+                
+                for (dir, reason) in &report.quarantined {
+                    eprintln!("sweep: quarantined {} ({})", dir.display(), reason);
+                }
+                
+                for (dir, reason) in &report.failed {
+                    eprintln!("sweep: failed {} ({})", dir.display(), reason);
+                }
+            }
+            Err(err) => eprintln!("sweep {}: {:?}", reconcile::CONTS_ROOT, err)
+        }
+    }
+    
+    let live: LiveInsts = Arc::new(Mutex::new(HashSet::new()));
+    
+    // nothing is live yet, so whatever's there is left over from a previous run
+    log_sweep(reconcile::sweep(&live));
+    reconcile::watch(live.clone(), log_sweep);
+    
     let mut stdin = io::stdin().lock();
     
     let mut insts: HashMap<usize, Inst> = HashMap::new();
@@ -288,26 +359,31 @@ fn main() {
         }
     }
     
+    // everything for the client but reload reports goes through here, so frames from different threads come out in the order they were made
     let (output_p, output_c) = mpsc::channel::<Vec<u8>>();
     
     thread::spawn(move || {
         while let Ok(output) = output_c.recv() {
-            io::stdout().lock().write_all(&output).unwrap();
+            let mut stdout = io::stdout().lock();
+            
+            // stdout is line buffered, and frames don't end in newlines
+            stdout.write_all(&output).unwrap();
+            stdout.flush().unwrap();
         }
     });
     
     loop {
         match byte!() {
             config_src @ (0x00 | 0x01) => {
+                let registry = registry.lock().unwrap().clone();
+                
                 let config = match config_src {
                     0x00 => {
                         let id_string = bytestring!();
 
-                        let lang_id = std::str::from_utf8(&id_string).unwrap();
-                        
-                        serde_json::from_reader(File::open(format!("/rto/imgs/configs/{}.json", lang_id)).unwrap()).unwrap()
+                        registry.get(&String::from_utf8_lossy(&id_string))
                     }
-                    0x01 => serde_json::from_slice(&bytestring!()).unwrap(),
+                    0x01 => registry.resolve_raw(&bytestring!()),
                     _ => unreachable!()
                 };
                 
@@ -318,38 +394,93 @@ fn main() {
                     _ => panic!()
                 };
                 
+                let config = match config {
+                    Ok(config) => config,
+                    Err(err) => {
+                        output_p.send([&[0x80u8, 0x01u8], format!("{:?}", err).as_bytes()].concat()).unwrap();
+                        
+                        continue;
+                    }
+                };
+                
                 let id = random_inst_id(&insts);
+                
+                // claimed before init touches /rto/conts, so the sweeper never sees it half made
+                live.lock().unwrap().insert(id.to_string());
 
-                match Inst::init(config, mode, id) {
+                match Inst::init(Emitter::new(output_p.clone(), id), config, mode) {
                     Ok(inst) => {
                         insts.insert(id, inst);
 
-                        io::stdout().lock().write_all(&[&[0x80u8, 0x00u8], &id.to_be_bytes()[..]].concat()).unwrap();
+                        output_p.send([&[0x80u8, 0x00u8], &id.to_be_bytes()[..]].concat()).unwrap();
+                    }
+                    Err(err) => {
+                        // whatever the rollback left behind is the sweeper's now
+                        live.lock().unwrap().remove(&id.to_string());
+                        
+                        output_p.send([&[0x80u8, 0x01u8], format!("{:?}", err).as_bytes()].concat()).unwrap();
                     }
-                    Err(err) => io::stdout().lock().write_all(&[&[0x80u8, 0x01u8], &format!("{:?}", err).as_bytes()[..]].concat()).unwrap()
                 }
             }
             0x10 => {
                 let inst_id = int!();
-                let inst = insts.get(&inst_id).unwrap().clone();
+                let inputs = bytestring!();
+                
+                let Some(inst) = insts.get(&inst_id).cloned() else {
+                    output_p.send([&[0x81u8, 0x01u8], &inst_id.to_be_bytes()[..], b"UnknownInst"].concat()).unwrap();
+                    
+                    continue;
+                };
+                
+                let output_p = output_p.clone();
                 
                 thread::spawn(move || {
-                    match inst.start() {
-                        Ok(()) => io::stdout().lock().write_all(&[&[0x81u8, 0x00u8], &inst_id.to_be_bytes()[..]].concat()).unwrap(),
-                        Err(err) => io::stdout().lock().write_all(&[&[0x81u8, 0x01u8], &inst_id.to_be_bytes()[..], &format!("{:?}", err).as_bytes()[..]].concat()).unwrap()
+                    match inst.start(&inputs) {
+                        Ok(outcomes) => output_p.send([&[0x81u8, 0x00u8], &inst_id.to_be_bytes()[..], &outcomes.iter().copied().map(Outcome::to_byte).collect::<Vec<u8>>()[..]].concat()).unwrap(),
+                        Err(err) => output_p.send([&[0x81u8, 0x01u8], &inst_id.to_be_bytes()[..], format!("{:?}", err).as_bytes()].concat()).unwrap()
                     }
                 });
             }
             0x11 => {
                 let inst_id = int!();
+                let cont_id = int!();
+                let stream = bytestring!();
+                let data = bytestring!();
+                
+                // nothing to say when it went fine
+                let result = match insts.get(&inst_id) {
+                    Some(inst) => inst.input(cont_id, &String::from_utf8_lossy(&stream), &data).map_err(|err| format!("{:?}", err)),
+                    None => Err("UnknownInst".to_owned())
+                };
                 
-                insts.get_mut(&inst_id).unwrap().input(int!(), &bytestring!());
+                if let Err(err) = result {
+                    output_p.send([&[0x87u8, 0x01u8], &inst_id.to_be_bytes()[..], err.as_bytes()].concat()).unwrap();
+                }
             }
             0x12 => {
                 let inst_id = int!();
                 
-                insts.get_mut(&inst_id).unwrap().stop();
+                let result = match insts.get(&inst_id) {
+                    Some(inst) => inst.stop().map_err(|err| {
+                        let forget = matches!(err, StopError::Destroy(_));
+                        
+                        (format!("{:?}", err), forget)
+                    }),
+                    None => Err(("UnknownInst".to_owned(), false))
+                };
+                
+                // once stopped, or failed to stop with its leftovers marked, it's no longer ours; the sweeper gets whatever's left
+                if matches!(result, Ok(()) | Err((_, true))) {
+                    insts.remove(&inst_id);
+                    live.lock().unwrap().remove(&inst_id.to_string());
+                }
+                
+                match result {
+                    Ok(()) => output_p.send([&[0x86u8, 0x00u8], &inst_id.to_be_bytes()[..]].concat()).unwrap(),
+                    Err((err, _)) => output_p.send([&[0x86u8, 0x01u8], &inst_id.to_be_bytes()[..], err.as_bytes()].concat()).unwrap()
+                }
             }
+            0x20 => write_reload_report(registry::reload(&registry, Path::new(registry::CONFIGS_ROOT))),
             _ => panic!()
         }
     }
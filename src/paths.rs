@@ -0,0 +1,419 @@
+use std::fs;
+use std::io;
+use std::ffi::CString;
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+// anything from the wire or from a config that ends up in a path or a mount option goes through here first
+
+pub const MAX_IDENT_LEN: usize = 64;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum IdentError {
+    Empty,
+    TooLong(usize),
+    BadChar(char),
+    DotPrefix
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum PathError {
+    NotRelative(PathBuf),
+    Canonicalize(PathBuf, io::Error),
+    Escapes(PathBuf)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum MountOptionError {
+    UnsafePath(PathBuf),
+    UnsafeOption(String)
+}
+
+// lang ids, config names and the like: [A-Za-z0-9._-], not starting with a dot, so never "..", never hidden, never a separator
+pub fn validate_ident(ident: &str) -> Result<&str, IdentError> {
+    if ident.is_empty() {
+        return Err(IdentError::Empty);
+    }
+
+    if ident.len() > MAX_IDENT_LEN {
+        return Err(IdentError::TooLong(ident.len()));
+    }
+
+    if let Some(bad) = ident.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))) {
+        return Err(IdentError::BadChar(bad));
+    }
+
+    if ident.starts_with('.') {
+        return Err(IdentError::DotPrefix);
+    }
+
+    Ok(ident)
+}
+
+// a path that's only plain components, for joining onto a root
+pub fn relative(path: &Path) -> Result<PathBuf, PathError> {
+    let mut rel = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir => {}
+            _ => return Err(PathError::NotRelative(path.to_owned()))
+        }
+    }
+
+    Ok(rel)
+}
+
+// root/rel with symlinks resolved, as long as it's still under root afterwards
+pub fn resolve_under(root: &Path, rel: &Path) -> Result<PathBuf, PathError> {
+    let root = fs::canonicalize(root).map_err(|err| PathError::Canonicalize(root.to_owned(), err))?;
+    let joined = root.join(relative(rel)?);
+    let resolved = fs::canonicalize(&joined).map_err(|err| PathError::Canonicalize(joined.clone(), err))?;
+
+    if resolved.starts_with(&root) {
+        Ok(resolved)
+    } else {
+        Err(PathError::Escapes(joined))
+    }
+}
+
+// overlay splits its options on ',' and lowerdir on ':', and treats '\' as an escape; none of those (or anything unprintable) get near it
+fn check_mount_path(path: &Path) -> Result<&str, MountOptionError> {
+    match path.to_str() {
+        Some(string) if path.is_absolute() && !string.bytes().any(|byte| matches!(byte, b',' | b':' | b'\\' | b'=') || byte.is_ascii_control()) => Ok(string),
+        _ => Err(MountOptionError::UnsafePath(path.to_owned()))
+    }
+}
+
+pub struct OverlayOptions<'a> {
+    pub lowerdirs: &'a [PathBuf], // topmost first
+    pub upperdir: Option<&'a Path>,
+    pub workdir: Option<&'a Path>,
+    pub flags: &'a [&'a str] // bare options like "volatile" or "index=off"
+}
+
+impl OverlayOptions<'_> {
+    pub fn to_cstring(&self) -> Result<CString, MountOptionError> {
+        let mut options: Vec<String> = Vec::new();
+
+        options.push(format!("lowerdir={}", self.lowerdirs.iter().map(|dir| check_mount_path(dir)).collect::<Result<Vec<&str>, _>>()?.join(":")));
+
+        if let Some(upperdir) = self.upperdir {
+            options.push(format!("upperdir={}", check_mount_path(upperdir)?));
+        }
+
+        if let Some(workdir) = self.workdir {
+            options.push(format!("workdir={}", check_mount_path(workdir)?));
+        }
+
+        for flag in self.flags {
+            if flag.is_empty() || !flag.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'=')) {
+                return Err(MountOptionError::UnsafeOption(flag.to_string()));
+            }
+
+            options.push(flag.to_string());
+        }
+
+        Ok(CString::new(options.join(",")).unwrap())
+    }
+}
+
+pub fn path_cstring(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+// opens path as the container sees it, with root as its /: symlinks, absolute ones included, and ".." resolve inside root and can't leave it
+pub fn open_in_root(root: &Path, path: &str, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+    let root_fd = fs::File::open(root)?;
+    let cs_path = CString::new(path).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = if flags & libc::O_CREAT != 0 { mode as u64 } else { 0 };
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+
+    let fd = unsafe { libc::syscall(libc::SYS_openat2, root_fd.as_raw_fd(), cs_path.as_ptr(), &how as *const libc::open_how, std::mem::size_of::<libc::open_how>()) };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { fs::File::from_raw_fd(fd as RawFd) })
+    }
+}
+
+// open_in_root for reading or writing a file's contents, where root is somewhere a container could have put a fifo or a symlink in the way
+// nothing but a regular file is let through, and nothing is followed or waited on to find out what it is
+pub fn open_file_in_root(root: &Path, path: &str, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+    let file = open_in_root(root, path, flags | libc::O_NONBLOCK | libc::O_NOFOLLOW, mode)?;
+
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+
+    let status = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+
+    check(status)?;
+    check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, status & !libc::O_NONBLOCK) })?;
+
+    Ok(file)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// a relative path as open_in_root takes it
+fn in_root(path: &Path) -> io::Result<&str> {
+    match path.to_str() {
+        Some("") => Ok("."),
+        Some(path) => Ok(path),
+        None => Err(io::Error::from(io::ErrorKind::InvalidInput))
+    }
+}
+
+// from's owner and mode, onto something that's just been made (or was already there) in root
+fn own(file: &fs::File, meta: &fs::Metadata) -> io::Result<()> {
+    check(unsafe { libc::fchown(file.as_raw_fd(), meta.uid(), meta.gid()) })?;
+    check(unsafe { libc::fchmod(file.as_raw_fd(), meta.mode() & 0o7777) })
+}
+
+// copies from, a host path, to `to` (relative, see relative) as the container under root sees it, like cp -a minus timestamps and hard links
+// every parent is resolved inside root and the last component is never followed, so no symlink in root, absolute ones included, can send the copy out to the host
+pub fn copy_into_root(from: &Path, root: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+
+    let (parent, name) = match to.file_name() {
+        Some(name) => (open_in_root(root, in_root(to.parent().unwrap())?, libc::O_PATH | libc::O_DIRECTORY, 0)?, CString::new(name.as_bytes())?),
+        // the root itself, which a dir can only be merged into
+        None if meta.is_dir() => return copy_dir(from, root, to, &meta),
+        None => return Err(io::Error::from(io::ErrorKind::InvalidInput))
+    };
+
+    if meta.is_dir() {
+        if let Err(err) = check(unsafe { libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), 0o700) }) {
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                return Err(err);
+            }
+        }
+
+        return copy_dir(from, root, to, &meta);
+    }
+
+    // replaced rather than written through
+    unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) };
+
+    if meta.file_type().is_symlink() {
+        let target = CString::new(fs::read_link(from)?.as_os_str().as_bytes())?;
+
+        check(unsafe { libc::symlinkat(target.as_ptr(), parent.as_raw_fd(), name.as_ptr()) })?;
+        check(unsafe { libc::fchownat(parent.as_raw_fd(), name.as_ptr(), meta.uid(), meta.gid(), libc::AT_SYMLINK_NOFOLLOW) })
+    } else if meta.is_file() {
+        let fd = unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC, 0o600) };
+
+        check(fd)?;
+
+        let mut out = unsafe { fs::File::from_raw_fd(fd) };
+
+        io::copy(&mut fs::File::open(from)?, &mut out)?;
+        own(&out, &meta)
+    } else {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+// the dir at to is there by now, if only as a symlink to one somewhere else in root
+fn copy_dir(from: &Path, root: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    own(&open_in_root(root, in_root(to)?, libc::O_RDONLY | libc::O_DIRECTORY, 0)?, meta)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+
+        copy_into_root(&entry.path(), root, &to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idents() {
+        assert!(validate_ident("python3.12").is_ok());
+        assert!(validate_ident("c-gcc_13").is_ok());
+
+        assert!(matches!(validate_ident(""), Err(IdentError::Empty)));
+        assert!(matches!(validate_ident(".."), Err(IdentError::DotPrefix)));
+        assert!(matches!(validate_ident(".hidden"), Err(IdentError::DotPrefix)));
+        assert!(matches!(validate_ident("../../etc/passwd"), Err(IdentError::BadChar('/'))));
+        assert!(matches!(validate_ident("a/b"), Err(IdentError::BadChar('/'))));
+        assert!(matches!(validate_ident("a\0b"), Err(IdentError::BadChar('\0'))));
+        assert!(matches!(validate_ident("py thon"), Err(IdentError::BadChar(' '))));
+        assert!(matches!(validate_ident(&"a".repeat(MAX_IDENT_LEN + 1)), Err(IdentError::TooLong(_))));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative(Path::new("a/./b")).unwrap(), PathBuf::from("a/b"));
+
+        assert!(matches!(relative(Path::new("../a")), Err(PathError::NotRelative(_))));
+        assert!(matches!(relative(Path::new("a/../../b")), Err(PathError::NotRelative(_))));
+        assert!(matches!(relative(Path::new("/etc")), Err(PathError::NotRelative(_))));
+    }
+
+    #[test]
+    fn resolve_stays_under_root() {
+        let dir = std::env::temp_dir().join(format!("rto-paths-{:016x}", rand::random::<u64>()));
+        let root = dir.join("root");
+
+        fs::create_dir_all(root.join("inside")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("inside", root.join("ok")).unwrap();
+
+        let resolved = resolve_under(&root, Path::new("ok"));
+        let escaped = resolve_under(&root, Path::new("escape"));
+        let dotted = resolve_under(&root, Path::new("../outside"));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(resolved.unwrap().ends_with("root/inside"));
+        assert!(matches!(escaped, Err(PathError::Escapes(_))));
+        assert!(matches!(dotted, Err(PathError::NotRelative(_))));
+    }
+
+    #[test]
+    fn copies_stay_in_root() {
+        let dir = std::env::temp_dir().join(format!("rto-paths-{:016x}", rand::random::<u64>()));
+        let root = dir.join("root");
+        let src = dir.join("src");
+
+        fs::create_dir_all(root.join("run")).unwrap();
+        fs::create_dir_all(root.join("var")).unwrap();
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(src.join("sub/file"), b"data").unwrap();
+        std::os::unix::fs::symlink("file", src.join("sub/link")).unwrap();
+        // the usual absolute one, and one aimed at the host
+        std::os::unix::fs::symlink("/run", root.join("var/run")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("escape")).unwrap();
+
+        copy_into_root(&src, &root, Path::new("var/run/x")).unwrap();
+        let escaped = copy_into_root(&src.join("sub/file"), &root, Path::new("escape/file"));
+
+        let copied = fs::read(root.join("run/x/sub/file")).unwrap();
+        let link = fs::read_link(root.join("run/x/sub/link")).unwrap();
+        let outside = fs::read_dir(dir.join("outside")).unwrap().count();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied, b"data");
+        assert_eq!(link, PathBuf::from("file"));
+        assert!(escaped.is_err());
+        assert_eq!(outside, 0);
+    }
+
+    #[test]
+    fn open_stays_in_root() {
+        let dir = std::env::temp_dir().join(format!("rto-paths-{:016x}", rand::random::<u64>()));
+        let root = dir.join("root");
+
+        fs::create_dir_all(root.join("tmp")).unwrap();
+        fs::write(dir.join("secret"), "host").unwrap();
+        fs::write(root.join("secret"), "container").unwrap();
+        std::os::unix::fs::symlink("/secret", root.join("tmp/abs")).unwrap();
+        std::os::unix::fs::symlink("../../secret", root.join("tmp/rel")).unwrap();
+
+        let read = |path: &str| open_in_root(&root, path, libc::O_RDONLY, 0).map(|file| std::io::read_to_string(file).unwrap());
+
+        let results = [read("/secret"), read("/tmp/abs"), read("/tmp/rel"), read("/../secret")];
+        let created = open_in_root(&root, "/tmp/new", libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o644).is_ok() && root.join("tmp/new").is_file();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        for result in results {
+            assert_eq!(result.unwrap(), "container");
+        }
+
+        assert!(created);
+    }
+
+    #[test]
+    fn opens_only_regular_files() {
+        let dir = std::env::temp_dir().join(format!("rto-paths-{:016x}", rand::random::<u64>()));
+        let root = dir.join("root");
+
+        fs::create_dir_all(root.join("tmp")).unwrap();
+        fs::write(root.join("tmp/file"), "data").unwrap();
+        std::os::unix::fs::symlink("file", root.join("tmp/link")).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path_cstring(&root.join("tmp/fifo")).as_ptr(), 0o644) }, 0);
+
+        // a fifo nothing's going to open the other end of would block either of these forever
+        let fifo_read = open_file_in_root(&root, "/tmp/fifo", libc::O_RDONLY, 0);
+        let fifo_write = open_file_in_root(&root, "/tmp/fifo", libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o644);
+        let dir_read = open_file_in_root(&root, "/tmp", libc::O_RDONLY, 0);
+        let link_read = open_file_in_root(&root, "/tmp/link", libc::O_RDONLY, 0);
+        let file = open_file_in_root(&root, "/tmp/file", libc::O_RDONLY, 0);
+        let flags = file.as_ref().map(|file| unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) });
+        let appended = open_file_in_root(&root, "/tmp/file", libc::O_WRONLY | libc::O_APPEND, 0).and_then(|mut file| io::Write::write_all(&mut file, b"more"));
+        let contents = fs::read_to_string(root.join("tmp/file"));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(fifo_read.is_err());
+        assert!(fifo_write.is_err());
+        assert!(dir_read.is_err());
+        assert!(link_read.is_err());
+        assert_eq!(flags.unwrap() & libc::O_NONBLOCK, 0);
+        assert!(appended.is_ok());
+        assert_eq!(contents.unwrap(), "datamore");
+    }
+
+    #[test]
+    fn overlay_options() {
+        let lowerdirs = [PathBuf::from("/rto/a"), PathBuf::from("/rto/b")];
+
+        let options = OverlayOptions {
+            lowerdirs: &lowerdirs,
+            upperdir: Some(Path::new("/rto/top")),
+            workdir: Some(Path::new("/rto/work")),
+            flags: &["volatile"]
+        };
+
+        assert_eq!(options.to_cstring().unwrap().to_str().unwrap(), "lowerdir=/rto/a:/rto/b,upperdir=/rto/top,workdir=/rto/work,volatile");
+
+        for bad in ["/rto/a,upperdir=/", "/rto/a:/etc", "/rto/a\\", "relative", "/rto/a\n"] {
+            let lowerdirs = [PathBuf::from(bad)];
+
+            let options = OverlayOptions {
+                lowerdirs: &lowerdirs,
+                upperdir: None,
+                workdir: None,
+                flags: &[]
+            };
+
+            assert!(matches!(options.to_cstring(), Err(MountOptionError::UnsafePath(_))), "{:?} accepted", bad);
+        }
+
+        let options = OverlayOptions {
+            lowerdirs: &lowerdirs,
+            upperdir: None,
+            workdir: None,
+            flags: &["ro,lowerdir=/"]
+        };
+
+        assert!(matches!(options.to_cstring(), Err(MountOptionError::UnsafeOption(_))));
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs;
+use std::io;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::thread;
+use serde_json::Value;
+
+use crate::container::runc_delete;
+use crate::gc::unescape_mountinfo;
+use crate::paths::path_cstring;
+
+// anything under /rto/conts (dirs, the mounts in them, rto_* runc containers) belongs to an owner: a live instance of this conductor, or a build whose pid is still alive
+// what has no owner is left over from a failed init or a crash, and gets torn down here: runc container first, then its mounts, then its dirs
+
+pub const CONTS_ROOT: &str = "/rto/conts";
+pub const QUARANTINE_ROOT: &str = "/rto/conts/.quarantine"; // same filesystem, so quarantining is just a rename
+pub const BROKEN_MARKER: &str = "broken"; // left in a container dir whose rollback didn't finish, so it's swept even while its instance lives
+
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+// ids of instances that are live (or being initted) in this conductor
+pub type LiveInsts = Arc<Mutex<HashSet<String>>>;
+
+#[derive(Default)]
+pub struct ReconcileReport {
+    pub deleted: Vec<String>, // runc containers
+    pub unmounted: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub quarantined: Vec<(PathBuf, String)>,
+    pub failed: Vec<(PathBuf, String)> // couldn't even be quarantined, tried again next time
+}
+
+// every mount point under root, in the order they were mounted
+fn mounts_under(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    Ok(mountinfo.lines().filter_map(|line| line.split(' ').nth(4)).map(|field| PathBuf::from(unescape_mountinfo(field))).filter(|mount| mount.starts_with(root)).collect())
+}
+
+// (owner, runc id) of every rto_* container runc knows about; None if runc couldn't be asked
+fn runc_containers() -> Option<Vec<(String, String)>> {
+    let output = Command::new("/usr/bin/runc").args(["list", "--format", "json"]).stdin(Stdio::null()).stderr(Stdio::null()).output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // an empty list comes back as null
+    let list: Vec<Value> = serde_json::from_slice::<Option<Vec<Value>>>(&output.stdout).ok()?.unwrap_or_default();
+
+    Some(list.iter().filter_map(|cont| cont.get("id")?.as_str()).filter_map(|runc_id| {
+        let (owner, _) = runc_id.strip_prefix("rto_")?.rsplit_once('_')?;
+
+        Some((owner.to_owned(), runc_id.to_owned()))
+    }).collect())
+}
+
+fn pid_alive(pid: libc::pid_t) -> bool {
+    pid > 0 && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+fn is_owned(owner: &str, live: &HashSet<String>) -> bool {
+    if live.contains(owner) {
+        return true;
+    }
+
+    // builds run in their own process and leave their pid behind
+    fs::read_to_string(Path::new(CONTS_ROOT).join(owner).join("owner")).ok().and_then(|pid| pid.trim().parse().ok()).is_some_and(pid_alive)
+}
+
+// moves dir out of the sweep's way, with a note on why, for someone to look at
+fn quarantine(dir: &Path, reason: String, report: &mut ReconcileReport) {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    let dest = Path::new(QUARANTINE_ROOT).join(format!("{}-{:016x}", name, rand::random::<u64>()));
+
+    match fs::create_dir_all(QUARANTINE_ROOT).and_then(|()| fs::rename(dir, &dest)) {
+        Ok(()) => {
+            let _ = fs::write(dest.join("quarantined"), format!("{}\n{}\n", dir.display(), reason));
+
+            report.quarantined.push((dest, reason));
+        }
+        Err(err) => report.failed.push((dir.to_owned(), format!("{}; quarantine: {:?}", reason, err)))
+    }
+}
+
+// runc containers, then mounts (last mounted first, so root comes off before the upper under it), then the dirs
+fn tear_down(dir: &Path, runc_ids: &[String], report: &mut ReconcileReport) {
+    for runc_id in runc_ids {
+        match runc_delete(runc_id) {
+            Ok(true) => report.deleted.push(runc_id.clone()),
+            Ok(false) => {}
+            Err(err) => return quarantine(dir, format!("runc delete {}: {:?}", runc_id, err), report)
+        }
+    }
+
+    let mounts = match mounts_under(dir) {
+        Ok(mounts) => mounts,
+        Err(err) => return report.failed.push((dir.to_owned(), format!("mountinfo: {:?}", err)))
+    };
+
+    for mount in mounts.iter().rev() {
+        if unsafe { libc::umount2(path_cstring(mount).as_ptr(), libc::MNT_DETACH) } == 0 {
+            report.unmounted.push(mount.clone());
+        }
+    }
+
+    // remove_dir_all would walk straight into anything still mounted
+    match mounts_under(dir) {
+        Ok(left) if left.is_empty() => {}
+        Ok(left) => return quarantine(dir, format!("still mounted: {:?}", left), report),
+        Err(err) => return report.failed.push((dir.to_owned(), format!("mountinfo: {:?}", err)))
+    }
+
+    match fs::remove_dir_all(dir) {
+        Ok(()) => report.removed.push(dir.to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => quarantine(dir, format!("remove: {:?}", err), report)
+    }
+}
+
+// one pass over /rto/conts, tearing down everything that isn't owned
+pub fn sweep(live: &LiveInsts) -> io::Result<ReconcileReport> {
+    let mut report = ReconcileReport::default();
+
+    let entries = match fs::read_dir(CONTS_ROOT) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(err) => return Err(err)
+    };
+
+    let runc = runc_containers();
+    let runc_listed = runc.is_some();
+    let mut runc = runc.unwrap_or_default();
+
+    for entry in entries {
+        let owner = entry?.file_name().to_string_lossy().into_owned();
+
+        // the quarantine, and builds that haven't written their owner yet
+        if owner.starts_with('.') {
+            continue;
+        }
+
+        // checked per owner, not once up front, so an instance created mid-sweep is seen
+        if is_owned(&owner, &live.lock().unwrap()) {
+            runc.retain(|(runc_owner, _)| *runc_owner != owner);
+
+            for cont in fs::read_dir(Path::new(CONTS_ROOT).join(&owner)).into_iter().flatten().flatten() {
+                if cont.path().join(BROKEN_MARKER).is_file() {
+                    tear_down(&cont.path(), &[format!("rto_{}_{}", owner, cont.file_name().to_string_lossy())], &mut report);
+                }
+            }
+
+            continue;
+        }
+
+        let runc_ids: Vec<String> = runc.iter().filter(|(runc_owner, _)| *runc_owner == owner).map(|(_, runc_id)| runc_id.clone()).collect();
+
+        runc.retain(|(runc_owner, _)| *runc_owner != owner);
+
+        // without a list, deleting by the name it would have had is the best there is
+        let runc_ids = if runc_listed {
+            runc_ids
+        } else {
+            fs::read_dir(Path::new(CONTS_ROOT).join(&owner)).into_iter().flatten().flatten().filter(|cont| cont.path().join("config.json").is_file()).map(|cont| format!("rto_{}_{}", owner, cont.file_name().to_string_lossy())).collect()
+        };
+
+        tear_down(&Path::new(CONTS_ROOT).join(&owner), &runc_ids, &mut report);
+    }
+
+    // containers whose dirs are already gone
+    for (owner, runc_id) in runc {
+        if !is_owned(&owner, &live.lock().unwrap()) && runc_delete(&runc_id).is_ok_and(|deleted| deleted) {
+            report.deleted.push(runc_id);
+        }
+    }
+
+    Ok(report)
+}
+
+// sweeps every RECONCILE_INTERVAL for as long as the conductor runs
+pub fn watch(live: LiveInsts, on_sweep: impl Fn(io::Result<ReconcileReport>) + Send + 'static) {
+    thread::spawn(move || loop {
+        thread::sleep(RECONCILE_INTERVAL);
+
+        on_sweep(sweep(&live));
+    });
+}
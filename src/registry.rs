@@ -0,0 +1,436 @@
+use std::fs;
+use std::io;
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::thread;
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, UpperLimits, User};
+use crate::inst::merge_env;
+use crate::layers::{Digest, LayerError};
+use crate::paths::{self, IdentError};
+use crate::staging::{self, Directive, StagingError};
+
+pub const CONFIGS_ROOT: &str = "/rto/imgs/configs";
+
+// a config as written on disk or sent by the client; `extends` is resolved away before anything runs it
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RawConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    #[serde(default)]
+    pub diffs: Vec<String>, // go on top of the base's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>, // override the base's by key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upper: Option<UpperLimits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging: Option<Vec<Directive>> // replaces the base's outright
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ConfigError {
+    BadId(IdentError),
+    Unknown(String),
+    Read(io::Error),
+    Parse(serde_json::Error),
+    UnknownBase(String),
+    BadBase(String),
+    Cycle(Vec<String>),
+    BadDiff(LayerError),
+    MissingLayer(Digest),
+    NoDiffs,
+    BadEnv(String),
+    RelativeCwd(String),
+    BadUmask(u32),
+    ZeroUpperLimit,
+    BadStaging(StagingError)
+}
+
+// every config under CONFIGS_ROOT, loaded, resolved and validated once
+// reloads build a whole new one and swap it in; instances hold onto the Arc<Config> they started with
+pub struct Registry {
+    configs: HashMap<String, Arc<Config>>,
+    rejected: Vec<(String, ConfigError)>
+}
+
+fn merge(base: Option<&Config>, raw: RawConfig) -> Config {
+    let mut env: Vec<String> = base.map(|base| base.env.clone()).unwrap_or_default();
+
+    merge_env(&mut env, &raw.env);
+
+    Config {
+        diffs: raw.diffs.into_iter().chain(base.into_iter().flat_map(|base| base.diffs.iter().cloned())).collect(),
+        env,
+        cwd: raw.cwd.or_else(|| base.and_then(|base| base.cwd.clone())),
+        user: raw.user.or_else(|| base.and_then(|base| base.user.clone())),
+        upper: raw.upper.or(base.map(|base| base.upper)).unwrap_or_default(),
+        staging: raw.staging.or_else(|| base.map(|base| base.staging.clone())).unwrap_or_default()
+    }
+}
+
+// whether a layer's in the store; contents get verified when the layer is first used, this only catches configs pointing at nothing
+type HasLayer = fn(&Digest) -> bool;
+
+fn in_store(digest: &Digest) -> bool {
+    digest.root().is_dir()
+}
+
+fn validate(config: &Config, has_layer: HasLayer) -> Result<(), ConfigError> {
+    if config.diffs.is_empty() {
+        return Err(ConfigError::NoDiffs);
+    }
+
+    // staging's spawned containers have to be able to mount theirs as much as the config's own
+    for diff in config.diffs.iter().chain(staging::spawn_stacks(&config.staging).into_iter().flatten()) {
+        let digest = Digest::parse(diff).map_err(ConfigError::BadDiff)?;
+
+        if !has_layer(&digest) {
+            return Err(ConfigError::MissingLayer(digest));
+        }
+    }
+
+    for var in &config.env {
+        if !matches!(var.split_once('='), Some((key, _)) if !key.is_empty()) {
+            return Err(ConfigError::BadEnv(var.clone()));
+        }
+    }
+
+    if let Some(cwd) = &config.cwd {
+        if !cwd.starts_with('/') {
+            return Err(ConfigError::RelativeCwd(cwd.clone()));
+        }
+    }
+
+    if let Some(umask) = config.user.as_ref().and_then(|user| user.umask).filter(|umask| *umask > 0o777) {
+        return Err(ConfigError::BadUmask(umask));
+    }
+
+    if config.upper.size == 0 || config.upper.inodes == 0 {
+        return Err(ConfigError::ZeroUpperLimit);
+    }
+
+    staging::validate(&config.staging).map_err(ConfigError::BadStaging)?;
+
+    Ok(())
+}
+
+struct Loader {
+    raws: HashMap<String, RawConfig>,
+    configs: HashMap<String, Arc<Config>>,
+    rejected: Vec<(String, ConfigError)>,
+    stack: Vec<String>,
+    has_layer: HasLayer
+}
+
+impl Loader {
+    // None if the config (or something it extends) got rejected
+    fn resolve(&mut self, id: &str) -> Option<Arc<Config>> {
+        if let Some(config) = self.configs.get(id) {
+            return Some(config.clone());
+        }
+
+        let raw = self.raws.remove(id)?;
+
+        self.stack.push(id.to_owned());
+
+        let result = match &raw.extends {
+            None => Ok(None),
+            Some(base_id) if paths::validate_ident(base_id).is_err() => Err(ConfigError::UnknownBase(base_id.clone())),
+            Some(base_id) if self.stack.contains(base_id) => Err(ConfigError::Cycle(self.stack.clone())),
+            Some(base_id) => match self.resolve(base_id) {
+                Some(base) => Ok(Some(base)),
+                None if self.rejected.iter().any(|(id, _)| id == base_id) => Err(ConfigError::BadBase(base_id.clone())),
+                None => Err(ConfigError::UnknownBase(base_id.clone()))
+            }
+        }.and_then(|base| {
+            let config = merge(base.as_deref(), raw);
+
+            validate(&config, self.has_layer)?;
+
+            Ok(Arc::new(config))
+        });
+
+        self.stack.pop();
+
+        match result {
+            Ok(config) => {
+                self.configs.insert(id.to_owned(), config.clone());
+
+                Some(config)
+            }
+            Err(err) => {
+                self.rejected.push((id.to_owned(), err));
+
+                None
+            }
+        }
+    }
+}
+
+impl Registry {
+    pub fn load(dir: &Path) -> io::Result<Registry> {
+        let mut raws: HashMap<String, RawConfig> = HashMap::new();
+        let mut rejected: Vec<(String, ConfigError)> = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            let id = match (path.file_stem(), path.extension()) {
+                (Some(stem), Some(ext)) if ext == "json" => stem.to_string_lossy().into_owned(),
+                _ => continue
+            };
+
+            if let Err(err) = paths::validate_ident(&id) {
+                rejected.push((id, ConfigError::BadId(err)));
+
+                continue;
+            }
+
+            match fs::read(&path).map_err(ConfigError::Read).and_then(|bytes| serde_json::from_slice(&bytes).map_err(ConfigError::Parse)) {
+                Ok(raw) => {
+                    raws.insert(id, raw);
+                }
+                Err(err) => rejected.push((id, err))
+            }
+        }
+
+        Ok(Registry::resolve_all(raws, rejected, in_store))
+    }
+
+    // rejected is what already failed before it came to resolving
+    fn resolve_all(raws: HashMap<String, RawConfig>, rejected: Vec<(String, ConfigError)>, has_layer: HasLayer) -> Registry {
+        let mut ids: Vec<String> = raws.keys().cloned().collect();
+
+        ids.sort();
+
+        let mut loader = Loader {
+            raws,
+            configs: HashMap::new(),
+            rejected,
+            stack: Vec::new(),
+            has_layer
+        };
+
+        for id in ids {
+            loader.resolve(&id);
+        }
+
+        Registry {
+            configs: loader.configs,
+            rejected: loader.rejected
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<Config>, ConfigError> {
+        paths::validate_ident(id).map_err(ConfigError::BadId)?;
+
+        self.configs.get(id).cloned().ok_or_else(|| ConfigError::Unknown(id.to_owned()))
+    }
+
+    pub fn configs(&self) -> impl Iterator<Item = (&String, &Arc<Config>)> {
+        self.configs.iter()
+    }
+
+    // configs that failed to load, and why
+    pub fn rejected(&self) -> &[(String, ConfigError)] {
+        &self.rejected
+    }
+
+    // a one-off config from the client, which may extend a registered one
+    pub fn resolve_raw(&self, bytes: &[u8]) -> Result<Arc<Config>, ConfigError> {
+        let raw: RawConfig = serde_json::from_slice(bytes).map_err(ConfigError::Parse)?;
+
+        let base = match &raw.extends {
+            Some(base_id) => Some(self.configs.get(base_id).ok_or_else(|| ConfigError::UnknownBase(base_id.clone()))?.clone()),
+            None => None
+        };
+
+        let config = merge(base.as_deref(), raw);
+
+        validate(&config, in_store)?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+pub type SharedRegistry = Arc<Mutex<Arc<Registry>>>;
+
+#[derive(Serialize, Default)]
+pub struct ReloadReport {
+    added: Vec<String>,
+    changed: Vec<String>,
+    removed: Vec<String>,
+    rejected: Vec<(String, String)>
+}
+
+impl ReloadReport {
+    fn between(old: &Registry, new: &Registry) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        for (id, config) in &new.configs {
+            match old.configs.get(id) {
+                None => report.added.push(id.clone()),
+                Some(old_config) if old_config != config => report.changed.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+
+        report.removed = old.configs.keys().filter(|id| !new.configs.contains_key(*id)).cloned().collect();
+        report.rejected = new.rejected.iter().map(|(id, err)| (id.clone(), format!("{:?}", err))).collect();
+
+        report.added.sort();
+        report.changed.sort();
+        report.removed.sort();
+
+        report
+    }
+}
+
+// all or nothing: if the directory can't be read the old registry stays
+pub fn reload(shared: &SharedRegistry, dir: &Path) -> io::Result<ReloadReport> {
+    let new = Registry::load(dir)?;
+    let mut current = shared.lock().unwrap();
+
+    let report = ReloadReport::between(&current, &new);
+
+    *current = Arc::new(new);
+
+    Ok(report)
+}
+
+// reloads whenever something in dir changes; bursts of events (editors, rsync) are coalesced into one reload
+pub fn watch(shared: SharedRegistry, dir: PathBuf, on_reload: impl Fn(io::Result<ReloadReport>) + Send + 'static) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let cs_dir = CString::new(dir.to_string_lossy().as_bytes()).unwrap();
+
+    if unsafe { libc::inotify_add_watch(fd, cs_dir.as_ptr(), libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE | libc::IN_CREATE) } < 0 {
+        let err = io::Error::last_os_error();
+
+        unsafe { libc::close(fd) };
+
+        return Err(err);
+    }
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0
+            };
+
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                continue;
+            }
+
+            thread::sleep(Duration::from_millis(250));
+
+            while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+
+            on_reload(reload(&shared, &dir));
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOWER: &str = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+    const UPPER: &str = "sha256:2222222222222222222222222222222222222222222222222222222222222222";
+    const GONE: &str = "sha256:3333333333333333333333333333333333333333333333333333333333333333";
+
+    fn present(digest: &Digest) -> bool {
+        digest.to_string() != GONE
+    }
+
+    fn registry(raws: &[(&str, serde_json::Value)]) -> Registry {
+        Registry::resolve_all(raws.iter().map(|(id, raw)| (id.to_string(), serde_json::from_value(raw.clone()).unwrap())).collect(), Vec::new(), present)
+    }
+
+    fn rejection<'r>(registry: &'r Registry, id: &str) -> Option<&'r ConfigError> {
+        registry.rejected().iter().find(|(rejected, _)| rejected == id).map(|(_, err)| err)
+    }
+
+    #[test]
+    fn extends_chains() {
+        let registry = registry(&[
+            ("base", serde_json::json!({"diffs": [LOWER], "env": ["PATH=/bin", "LANG=C", "HOME=/"], "cwd": "/base"})),
+            ("mid", serde_json::json!({"extends": "base", "diffs": [UPPER], "env": ["LANG=C.UTF-8"]})),
+            ("top", serde_json::json!({"extends": "mid", "env": ["PATH=/usr/bin:/bin", "TERM=dumb"], "cwd": "/top"}))
+        ]);
+
+        let top = registry.get("top").unwrap();
+
+        assert!(registry.rejected().is_empty());
+        assert_eq!(top.diffs, [UPPER, LOWER]);
+        // overridden by key, whatever the value has in it, and moved to the end
+        assert_eq!(top.env, ["HOME=/", "LANG=C.UTF-8", "PATH=/usr/bin:/bin", "TERM=dumb"]);
+        assert_eq!(top.cwd.as_deref(), Some("/top"));
+        assert_eq!(registry.get("mid").unwrap().cwd.as_deref(), Some("/base"));
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let registry = registry(&[
+            ("a", serde_json::json!({"extends": "b", "diffs": [LOWER]})),
+            ("b", serde_json::json!({"extends": "a", "diffs": [UPPER]})),
+            ("c", serde_json::json!({"extends": "c", "diffs": [LOWER]}))
+        ]);
+
+        assert_eq!(registry.configs().count(), 0);
+        assert!(matches!(rejection(&registry, "a"), Some(ConfigError::BadBase(base)) if base == "b"));
+        assert!(matches!(rejection(&registry, "b"), Some(ConfigError::Cycle(stack)) if stack == &["a", "b"]));
+        assert!(matches!(rejection(&registry, "c"), Some(ConfigError::Cycle(_))));
+    }
+
+    #[test]
+    fn children_of_rejected_bases_are_rejected() {
+        let registry = registry(&[
+            ("empty", serde_json::json!({})),
+            ("missing", serde_json::json!({"diffs": [GONE]})),
+            ("on_empty", serde_json::json!({"extends": "empty", "diffs": [LOWER]})),
+            ("on_missing", serde_json::json!({"extends": "missing", "diffs": [LOWER]})),
+            ("on_nothing", serde_json::json!({"extends": "nothing", "diffs": [LOWER]}))
+        ]);
+
+        assert_eq!(registry.configs().count(), 0);
+        assert!(matches!(rejection(&registry, "empty"), Some(ConfigError::NoDiffs)));
+        assert!(matches!(rejection(&registry, "missing"), Some(ConfigError::MissingLayer(digest)) if digest.to_string() == GONE));
+        assert!(matches!(rejection(&registry, "on_empty"), Some(ConfigError::BadBase(base)) if base == "empty"));
+        assert!(matches!(rejection(&registry, "on_missing"), Some(ConfigError::BadBase(base)) if base == "missing"));
+        assert!(matches!(rejection(&registry, "on_nothing"), Some(ConfigError::UnknownBase(base)) if base == "nothing"));
+    }
+
+    #[test]
+    fn umasks_are_range_checked() {
+        let registry = registry(&[
+            ("group", serde_json::json!({"diffs": [LOWER], "user": {"uid": 1000, "gid": 1000, "umask": 0o027}})),
+            ("setuid", serde_json::json!({"diffs": [LOWER], "user": {"uid": 1000, "gid": 1000, "umask": 0o7777}})),
+            ("on_group", serde_json::json!({"extends": "group", "user": {"uid": 1000, "gid": 1000, "umask": 0o1000}}))
+        ]);
+
+        assert!(registry.get("group").is_ok());
+        assert!(matches!(rejection(&registry, "setuid"), Some(ConfigError::BadUmask(0o7777))));
+        assert!(matches!(rejection(&registry, "on_group"), Some(ConfigError::BadUmask(0o1000))));
+    }
+}
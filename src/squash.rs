@@ -0,0 +1,192 @@
+use std::fs;
+use std::io;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use sha2::{Digest as _, Sha256};
+
+use crate::layers::{self, Digest, LayerError};
+use crate::paths::path_cstring;
+
+// mount(2) hands overlay its options in a single page, and overlay won't stack more than 500 lowerdirs
+// stacks past either limit get merged into one cached layer, keyed by the digests in the stack
+
+pub const SQUASHED_ROOT: &str = "/rto/imgs/squashed";
+
+const MAX_LOWERDIRS: usize = 500;
+const MAX_OPTIONS_LEN: usize = 4095;
+const RESERVED_OPTIONS_LEN: usize = 512; // upperdir, workdir and flags
+
+// the key a stack's squashed layer lives under
+pub fn stack_key(digests: &[Digest]) -> String {
+    let mut hasher = Sha256::new();
+
+    for digest in digests {
+        hasher.update(digest.to_string().as_bytes());
+        hasher.update(b"\n");
+    }
+
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn stack_file(digests: &[Digest]) -> String {
+    digests.iter().map(|digest| format!("{}\n", digest)).collect()
+}
+
+pub fn too_long(lowerdirs: &[PathBuf]) -> bool {
+    let len = "lowerdir=".len() + lowerdirs.iter().map(|dir| dir.as_os_str().len() + 1).sum::<usize>();
+
+    lowerdirs.len() > MAX_LOWERDIRS || len + RESERVED_OPTIONS_LEN > MAX_OPTIONS_LEN
+}
+
+// the lowerdirs to mount for a stack of layers (topmost first): each layer, or a single squashed one when that would be too long
+pub fn lowerdirs(digests: &[Digest]) -> Result<Vec<PathBuf>, LayerError> {
+    let lowerdirs: Vec<PathBuf> = digests.iter().map(layers::resolve).collect::<Result<_, _>>()?;
+
+    if !too_long(&lowerdirs) {
+        return Ok(lowerdirs);
+    }
+
+    let dir = PathBuf::from(format!("{}/{}", SQUASHED_ROOT, stack_key(digests)));
+
+    // the stack file doubles as the sealed marker, it's only written once the merge is complete
+    if fs::read_to_string(dir.join("stack")).is_ok_and(|stack| stack == stack_file(digests)) {
+        return Ok(vec![dir.join("root")]);
+    }
+
+    let staging = PathBuf::from(format!("{}/tmp/{:016x}", SQUASHED_ROOT, rand::random::<u64>()));
+
+    let result = (|| {
+        fs::create_dir_all(staging.join("root")).map_err(|err| LayerError::Squash(staging.clone(), err))?;
+
+        // bottom up, so each layer's whiteouts and opaque dirs apply to what's already merged
+        for lowerdir in lowerdirs.iter().rev() {
+            merge_dir(lowerdir, &staging.join("root")).map_err(|err| LayerError::Squash(lowerdir.clone(), err))?;
+        }
+
+        fs::write(staging.join("stack"), stack_file(digests)).map_err(|err| LayerError::Squash(staging.clone(), err))?;
+
+        // only a leftover that isn't this stack's gets replaced; one that is was squashed by someone else in the meantime, and may already be mounted
+        if fs::symlink_metadata(&dir).is_ok() && !fs::read_to_string(dir.join("stack")).is_ok_and(|stack| stack == stack_file(digests)) {
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        match fs::rename(&staging, &dir) {
+            Ok(()) => Ok(()),
+            // someone else squashed the same stack first
+            Err(_) if fs::read_to_string(dir.join("stack")).is_ok_and(|stack| stack == stack_file(digests)) => Ok(()),
+            Err(err) => Err(LayerError::Squash(dir.clone(), err))
+        }
+    })();
+
+    let _ = fs::remove_dir_all(&staging);
+
+    result.map(|()| vec![dir.join("root")])
+}
+
+fn is_whiteout(meta: &fs::Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err)
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// ownership, mode and non-overlay xattrs (file capabilities and the like)
+fn copy_attrs(src: &Path, dst: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let cs_src = path_cstring(src);
+    let cs_dst = path_cstring(dst);
+
+    check(unsafe { libc::lchown(cs_dst.as_ptr(), meta.uid(), meta.gid()) })?;
+
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+
+    let mut names = vec![0u8; 4096];
+    let len = unsafe { libc::llistxattr(cs_src.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+
+    if len <= 0 {
+        return Ok(());
+    }
+
+    for name in names[..len as usize].split(|byte| *byte == 0).filter(|name| !name.is_empty() && !name.starts_with(b"trusted.overlay.")) {
+        let cs_name = CString::new(name).unwrap();
+        let mut value = vec![0u8; 65536];
+        let value_len = unsafe { libc::lgetxattr(cs_src.as_ptr(), cs_name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+
+        if value_len >= 0 {
+            check(unsafe { libc::lsetxattr(cs_dst.as_ptr(), cs_name.as_ptr(), value.as_ptr() as *const libc::c_void, value_len as usize, 0) })?;
+        }
+    }
+
+    Ok(())
+}
+
+// applies one layer on top of what's been merged into dst so far, the way overlay would show it
+fn merge_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        let meta = fs::symlink_metadata(&src_path)?;
+        let file_type = meta.file_type();
+
+        if is_whiteout(&meta) {
+            remove(&dst_path)?;
+
+            continue;
+        }
+
+        if file_type.is_dir() {
+            let existing = fs::symlink_metadata(&dst_path);
+
+            // an opaque dir hides everything below it, anything but a dir is just replaced
+            if layers::opaque_xattr(&src_path) == b"y" || existing.as_ref().is_ok_and(|existing| !existing.is_dir()) {
+                remove(&dst_path)?;
+            }
+
+            if !dst_path.is_dir() {
+                fs::create_dir(&dst_path)?;
+            }
+
+            merge_dir(&src_path, &dst_path)?;
+            copy_attrs(&src_path, &dst_path, &meta)?;
+
+            continue;
+        }
+
+        remove(&dst_path)?;
+
+        let cs_dst = path_cstring(&dst_path);
+
+        if file_type.is_file() {
+            fs::copy(&src_path, &dst_path)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&src_path)?, &dst_path)?;
+        } else if file_type.is_fifo() {
+            check(unsafe { libc::mkfifo(cs_dst.as_ptr(), 0o600) })?;
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            check(unsafe { libc::mknod(cs_dst.as_ptr(), meta.mode(), meta.rdev()) })?;
+        } else {
+            continue; // sockets
+        }
+
+        copy_attrs(&src_path, &dst_path, &meta)?;
+    }
+
+    Ok(())
+}
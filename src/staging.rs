@@ -0,0 +1,1571 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use serde::{Deserialize, Serialize};
+
+use crate::{UpperLimits, User};
+use crate::container::{Container, ContainerInitError, ExecError, Outcome, ProcessSpec, RunError};
+use crate::layers::{Digest, LayerError};
+use crate::lifecycle::ExitStatus;
+use crate::paths;
+
+// what a language config does with a container once it's started, in order: write the submission in, run things, send files and output back
+// every process is exec'd into the container, which never runs its own
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Directive {
+    WriteFile {
+        file: String, // as the container sees it
+        src: Source,
+        #[serde(default)]
+        append: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exists: Option<bool>, // must (or mustn't) already be there
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<Owner>
+    },
+    OutputFile {
+        file: String,
+        dst: Dest
+    },
+    Run {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>, // for conditions on how it exited
+        run: String,
+        #[serde(default)]
+        args: Vec<Arg>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<User>, // the container's if left out
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        env: Vec<Arg>, // each KEY=value once built; an expand's template has to start with its KEY=
+        #[serde(default)]
+        unchecked_args: bool, // lets args and env be read whole from a value or a file, which nothing checks
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stdin: Option<Source>, // nothing if left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stdout: Option<Box<Dest>>, // ignored if left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stderr: Option<Box<Dest>>,
+        // how its exit code decides whether it failed; any nonzero one does, unless one of these is set
+        #[serde(default)]
+        ignore_code: bool, // never
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        success_codes: Option<Vec<i32>>, // any but these
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fail_codes: Option<Vec<i32>> // only these
+    },
+    CloseStream {
+        stream: String
+    },
+    // branches run alongside each other, each in order; a branch fails when it errors or one of its runs fails
+    Simul {
+        simul: Vec<Vec<Directive>>,
+        #[serde(default)]
+        finish_on_first_fail: bool, // no branch starts another directive
+        #[serde(default)]
+        kill_on_first_fail: bool, // every running process is killed
+        #[serde(default)]
+        stop_file_io_on_first_fail: bool // files, streams and captures stop taking or giving data
+    },
+    Conditional {
+        condition: Condition,
+        directives: Vec<Directive>
+    },
+    // another container in the instance, from its own layers, which its directives run in; later directives can get at its files by name
+    SpawnContainer {
+        name: String,
+        diffs: Vec<String>, // topmost first
+        #[serde(default)]
+        container_config: SpawnConfig,
+        #[serde(default)]
+        directives: Vec<Directive>
+    },
+    // only last, at the top: everything before it runs once in a base container, then each case gets a container of its own on top of what the base wrote
+    ForkCases {
+        directives: Vec<Directive>, // per case, with the case's values
+        #[serde(default)]
+        simul: bool // cases run alongside each other rather than one after another
+    }
+}
+
+// checked against what's happened so far in the run
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    Exited {
+        run: String, // the run's id; false if it hasn't run
+        codes: Vec<i32> // killed by a signal matches none
+    },
+    Succeeded {
+        run: String // by its directive's codes; false if it hasn't run
+    },
+    FileExists {
+        file: String
+    },
+    Matches {
+        capture: String,
+        pattern: String // the whole capture, with * for any bytes and ? for any one
+    },
+    NonEmpty {
+        capture: String
+    },
+    Flag {
+        flag: String // a value the client sent with start, set if it isn't empty
+    },
+    And {
+        and: Vec<Condition>
+    },
+    Or {
+        or: Vec<Condition>
+    },
+    Not {
+        not: Box<Condition>
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Source {
+    Const {
+        #[serde(rename = "const")]
+        value: String
+    },
+    String {
+        string: String // a value captured earlier in the run, or else one the client sent with start
+    },
+    Stream {
+        stream: String // read until the client closes it
+    },
+    Concat {
+        concat: Vec<Source>
+    },
+    File {
+        file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        container: Option<String> // a spawned container's name, or main for the one staging started in; this one if left out
+    },
+    Pipe {
+        pipe: String // read until whatever writes it is done
+    }
+}
+
+// who a file the conductor writes ends up belonging to, so the runs that need it can get at it and the rest can't
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32> // permission bits, 0644 if left out
+}
+
+impl Owner {
+    fn apply(&self, file: &File) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+
+        if unsafe { libc::fchown(fd, self.uid, self.gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // after the chown, which clears setuid and setgid bits
+        if unsafe { libc::fchmod(fd, self.mode.unwrap_or(0o644)) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+// a spawned container's own settings, where the config's don't fit
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SpawnConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upper: Option<UpperLimits>
+}
+
+// an arg or env var, as written or built from a source
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum Arg {
+    Literal(String),
+    Source(Source),
+    Expand(Expand) // any number of them
+}
+
+// args (or env vars) made from a value word by word, for what a config lets clients pass through and nothing more
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Expand {
+    pub expand: String, // a value captured earlier in the run, or else one the client sent; no words at all if there's neither
+    #[serde(default)]
+    pub split: Split,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>, // globs every word has to match; if left out, just no word can start with -
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>, // words
+    #[serde(default)]
+    pub escape: Escape,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join: Option<String>, // the words go in as one, with this between them
+    #[serde(default = "Expand::default_template")]
+    pub template: String // each word lands where its {} is
+}
+
+impl Expand {
+    fn default_template() -> String {
+        "{}".to_owned()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Split {
+    #[default]
+    Whole,
+    Whitespace,
+    Lines,
+    Nul
+}
+
+// done to each word once it's been allowed, before it goes in the template
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Escape {
+    #[default]
+    None,
+    Shell // single quoted, for templates that end up in a script
+}
+
+// named values the client sent with start, for one case
+pub type Values = HashMap<String, Vec<u8>>;
+
+// where output goes; past its limit, if it has one, the rest is dropped
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Dest {
+    Ignore,
+    Stream {
+        stream: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>
+    },
+    Capture {
+        capture: String, // the value it's kept as, for later sources
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>
+    },
+    File {
+        file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        container: Option<String>,
+        #[serde(default)]
+        append: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<Owner>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>
+    },
+    Pipe {
+        pipe: String, // closed once the output's done
+        limit: u64 // not optional: it's all buffered until something reads it
+    },
+    Tee {
+        tee: Vec<Dest>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u64>
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum StagingError {
+    BadDirective(String),
+    OpenFile(String, io::Error),
+    OwnFile(String, io::Error),
+    WriteFile(String, io::Error),
+    ReadFile(String, io::Error),
+    UnknownValue(String),
+    ReadSource(io::Error),
+    BadArg(String),
+    NotAllowed(String), // a word an expand's allowlist doesn't cover
+    TooManyWords(String, usize),
+    Exec(String, ExecError),
+    Simul(Vec<(usize, StagingError)>), // by branch
+    UnknownContainer(String),
+    NameTaken(String),
+    SpawnLayer(LayerError),
+    SpawnInit(ContainerInitError),
+    Stopped, // the instance was, while a container was being spawned
+    Spawned(String, Box<RunError>)
+}
+
+// makes spawn_container's containers; they're the instance's, and torn down with the rest of it
+pub trait Spawn: Sync {
+    fn spawn(&self, diffs: &[String], config: &SpawnConfig) -> Result<Arc<Container>, StagingError>;
+}
+
+// every diff stack a spawn_container in directives uses
+pub fn spawn_stacks(directives: &[Directive]) -> Vec<&[String]> {
+    directives.iter().flat_map(|directive| match directive {
+        Directive::SpawnContainer { diffs, directives, .. } => [&diffs[..]].into_iter().chain(spawn_stacks(directives)).collect(),
+        Directive::Simul { simul, .. } => simul.iter().flat_map(|branch| spawn_stacks(branch)).collect(),
+        Directive::Conditional { directives, .. } | Directive::ForkCases { directives, .. } => spawn_stacks(directives),
+        _ => Vec::new()
+    }).collect()
+}
+
+// how each branch of a simul went, sent to the client once they've all finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchOutcome {
+    Succeeded,
+    Failed,
+    Cancelled, // stopped short because another branch failed
+    Errored
+}
+
+impl BranchOutcome {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            BranchOutcome::Succeeded => 0x00,
+            BranchOutcome::Failed => 0x01,
+            BranchOutcome::Cancelled => 0x02,
+            BranchOutcome::Errored => 0x03
+        }
+    }
+}
+
+fn validate_dest(dest: &Dest) -> Result<(), StagingError> {
+    match dest {
+        Dest::Ignore | Dest::Stream { .. } | Dest::Pipe { .. } => Ok(()),
+        Dest::Capture { capture, .. } if capture.is_empty() => Err(StagingError::BadDirective("empty capture name".to_owned())),
+        Dest::Capture { .. } => Ok(()),
+        Dest::File { file, .. } if !file.starts_with('/') || file.contains('\0') => Err(StagingError::BadDirective(format!("not an absolute path: {:?}", file))),
+        Dest::File { owner, .. } => validate_owner(owner),
+        Dest::Tee { tee, .. } => tee.iter().try_for_each(validate_dest)
+    }
+}
+
+fn validate_owner(owner: &Option<Owner>) -> Result<(), StagingError> {
+    match owner.and_then(|owner| owner.mode) {
+        Some(mode) if mode > 0o7777 => Err(StagingError::BadDirective(format!("not a file mode: {:o}", mode))),
+        _ => Ok(())
+    }
+}
+
+fn validate_source(source: &Source) -> Result<(), StagingError> {
+    match source {
+        Source::File { file, .. } if !file.starts_with('/') || file.contains('\0') => Err(StagingError::BadDirective(format!("not an absolute path: {:?}", file))),
+        Source::Concat { concat } => concat.iter().try_for_each(validate_source),
+        _ => Ok(())
+    }
+}
+
+fn validate_condition(condition: &Condition) -> Result<(), StagingError> {
+    match condition {
+        Condition::FileExists { file } if !file.starts_with('/') || file.contains('\0') => Err(StagingError::BadDirective(format!("not an absolute path: {:?}", file))),
+        Condition::And { and: conditions } | Condition::Or { or: conditions } => conditions.iter().try_for_each(validate_condition),
+        Condition::Not { not } => validate_condition(not),
+        _ => Ok(())
+    }
+}
+
+// * matches any run of bytes, ? any one byte
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // where the last * was, and where in text it's matched up to
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&byte) if byte == b'?' || byte == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the last * take one more byte and try again from there
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+// whether a run went the way its directive wanted; a signal is always a failure unless the code is ignored
+fn succeeded(status: ExitStatus, ignore_code: bool, success_codes: Option<&[i32]>, fail_codes: Option<&[i32]>) -> bool {
+    match status {
+        _ if ignore_code => true,
+        ExitStatus::Signal(_) => false,
+        ExitStatus::Code(code) => match (success_codes, fail_codes) {
+            (Some(success_codes), _) => success_codes.contains(&code),
+            (None, Some(fail_codes)) => !fail_codes.contains(&code),
+            (None, None) => code == 0
+        }
+    }
+}
+
+fn validate_arg(arg: &Arg) -> Result<(), StagingError> {
+    match arg {
+        Arg::Literal(_) => Ok(()),
+        Arg::Source(source) => validate_source(source),
+        Arg::Expand(Expand { template, .. }) if template.matches("{}").count() != 1 => Err(StagingError::BadDirective(format!("template without exactly one {{}}: {:?}", template))),
+        Arg::Expand(_) => Ok(())
+    }
+}
+
+// the words of a value, each checked and escaped then put in the template
+fn expand(expand: &Expand, value: Option<&[u8]>) -> Result<Vec<String>, StagingError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(Vec::new())
+    };
+
+    let words: Vec<&[u8]> = match expand.split {
+        Split::Whole => vec![value],
+        Split::Whitespace => value.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty()).collect(),
+        Split::Lines => value.split(|byte| *byte == b'\n').filter(|word| !word.is_empty()).collect(),
+        Split::Nul => value.split(|byte| *byte == 0).filter(|word| !word.is_empty()).collect()
+    };
+
+    if let Some(max) = expand.max.filter(|max| words.len() > *max) {
+        return Err(StagingError::TooManyWords(expand.expand.clone(), max));
+    }
+
+    let words: Vec<String> = words.into_iter().map(|word| {
+        let word = match std::str::from_utf8(word) {
+            Ok(word) if !word.contains('\0') => word,
+            _ => return Err(StagingError::BadArg(String::from_utf8_lossy(word).into_owned()))
+        };
+
+        let allowed = match &expand.allow {
+            Some(allow) => allow.iter().any(|pattern| glob(pattern.as_bytes(), word.as_bytes())),
+            None => !word.starts_with('-')
+        };
+
+        if !allowed {
+            return Err(StagingError::NotAllowed(word.to_owned()));
+        }
+
+        Ok(match expand.escape {
+            Escape::None => word.to_owned(),
+            Escape::Shell => format!("'{}'", word.replace('\'', "'\\''"))
+        })
+    }).collect::<Result<_, _>>()?;
+
+    Ok(match &expand.join {
+        Some(_) if words.is_empty() => Vec::new(),
+        Some(join) => vec![expand.template.replacen("{}", &words.join(join), 1)],
+        None => words.iter().map(|word| expand.template.replacen("{}", word, 1)).collect()
+    })
+}
+
+fn validate_umask(user: &Option<User>) -> Result<(), StagingError> {
+    match user.as_ref().and_then(|user| user.umask) {
+        Some(umask) if umask > 0o777 => Err(StagingError::BadDirective(format!("not a umask: {:o}", umask))),
+        _ => Ok(())
+    }
+}
+
+// a source that's the same every run, which is all args and env can be read from without unchecked_args
+fn fixed(source: &Source) -> bool {
+    match source {
+        Source::Const { .. } => true,
+        Source::Concat { concat } => concat.iter().all(fixed),
+        _ => false
+    }
+}
+
+fn env_var(var: &str) -> Result<(), StagingError> {
+    match var.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(()),
+        _ => Err(StagingError::BadDirective(format!("bad env var: {:?}", var)))
+    }
+}
+
+// checked when the config is loaded, so nothing is found wrong halfway through a run
+pub fn validate(directives: &[Directive]) -> Result<(), StagingError> {
+    match directives.split_last() {
+        Some((Directive::ForkCases { directives: per_case, .. }, shared)) => {
+            validate_nested(shared)?;
+            validate_nested(per_case)
+        }
+        _ => validate_nested(directives)
+    }
+}
+
+// the directives before a fork_cases, and the fork_cases' own (with whether they're simul), if there is one
+pub fn split_fork(directives: &[Directive]) -> Option<(&[Directive], &[Directive], bool)> {
+    match directives.split_last() {
+        Some((Directive::ForkCases { directives: per_case, simul }, shared)) => Some((shared, per_case, *simul)),
+        _ => None
+    }
+}
+
+fn validate_nested(directives: &[Directive]) -> Result<(), StagingError> {
+    let absolute = |path: &str| if path.starts_with('/') && !path.contains('\0') {
+        Ok(())
+    } else {
+        Err(StagingError::BadDirective(format!("not an absolute path: {:?}", path)))
+    };
+
+    for directive in directives {
+        match directive {
+            Directive::WriteFile { file, src, owner, .. } => {
+                absolute(file)?;
+                validate_source(src)?;
+                validate_owner(owner)?;
+            }
+            Directive::OutputFile { file, dst } => {
+                absolute(file)?;
+                validate_dest(dst)?;
+            }
+            Directive::Run { run, args, cwd, user, env, unchecked_args, stdin, stdout, stderr, ignore_code, success_codes, fail_codes, .. } => {
+                if [*ignore_code, success_codes.is_some(), fail_codes.is_some()].into_iter().filter(|set| *set).count() > 1 {
+                    return Err(StagingError::BadDirective("more than one of ignore_code, success_codes and fail_codes".to_owned()));
+                }
+
+                validate_umask(user)?;
+
+                for dest in stdout.iter().chain(stderr) {
+                    validate_dest(dest)?;
+                }
+
+                if let Some(stdin) = stdin {
+                    validate_source(stdin)?;
+                }
+
+                if run.is_empty() {
+                    return Err(StagingError::BadDirective("empty run".to_owned()));
+                }
+
+                if let Some(cwd) = cwd {
+                    absolute(cwd)?;
+                }
+
+                for arg in args.iter().chain(env) {
+                    validate_arg(arg)?;
+
+                    if let Arg::Source(source) = arg {
+                        if !unchecked_args && !fixed(source) {
+                            return Err(StagingError::BadDirective(format!("an arg read from {:?} without unchecked_args", source)));
+                        }
+                    }
+                }
+
+                // built ones are checked once they're built
+                for var in env {
+                    match var {
+                        Arg::Literal(var) => env_var(var)?,
+                        // the client gets to pick the value and never the variable
+                        Arg::Expand(Expand { template, .. }) => match template.split_once('=') {
+                            Some((key, _)) if !key.is_empty() && !key.contains("{}") => {}
+                            _ => return Err(StagingError::BadDirective(format!("env template without a KEY= of its own: {:?}", template)))
+                        },
+                        Arg::Source(_) => {}
+                    }
+                }
+            }
+            Directive::CloseStream { .. } => {}
+            Directive::Simul { simul, .. } => {
+                if simul.is_empty() {
+                    return Err(StagingError::BadDirective("simul without branches".to_owned()));
+                }
+
+                for branch in simul {
+                    validate_nested(branch)?;
+                }
+            }
+            Directive::Conditional { condition, directives } => {
+                validate_condition(condition)?;
+                validate_nested(directives)?;
+            }
+            Directive::SpawnContainer { name, diffs, container_config, directives } => {
+                if name.is_empty() || name == "main" {
+                    return Err(StagingError::BadDirective(format!("bad container name: {:?}", name)));
+                }
+
+                if diffs.is_empty() {
+                    return Err(StagingError::BadDirective(format!("{} has no diffs", name)));
+                }
+
+                for diff in diffs {
+                    Digest::parse(diff).map_err(StagingError::SpawnLayer)?;
+                }
+
+                for var in &container_config.env {
+                    env_var(var)?;
+                }
+
+                if let Some(cwd) = &container_config.cwd {
+                    absolute(cwd)?;
+                }
+
+                validate_umask(&container_config.user)?;
+
+                if container_config.upper.is_some_and(|upper| upper.size == 0 || upper.inodes == 0) {
+                    return Err(StagingError::BadDirective(format!("{} has a zero upper limit", name)));
+                }
+
+                validate_nested(directives)?;
+            }
+            Directive::ForkCases { .. } => return Err(StagingError::BadDirective("fork_cases anywhere but last at the top".to_owned()))
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct StreamBuf {
+    data: VecDeque<u8>,
+    closed: bool
+}
+
+#[derive(Default)]
+struct Inputs {
+    streams: HashMap<String, StreamBuf>,
+    shut: bool // every stream, including ones nothing's been sent on yet
+}
+
+const UNTIL_POLL: Duration = Duration::from_millis(10); // how often a stream read with an until checks it
+
+// named streams between the client and a container: input the client sends whenever it likes, read by whichever directive wants it, and which outputs have been closed
+#[derive(Default)]
+pub struct ClientStreams {
+    inputs: Mutex<Inputs>,
+    arrived: Condvar,
+    closed_outputs: Mutex<HashSet<String>>
+}
+
+#[derive(Debug)]
+pub struct StreamClosed;
+
+impl ClientStreams {
+    // data for the stream, buffered until read; empty data closes it
+    pub fn push(&self, stream: &str, data: &[u8]) -> Result<(), StreamClosed> {
+        let mut inputs = self.inputs.lock().unwrap();
+
+        if inputs.shut {
+            return Err(StreamClosed);
+        }
+
+        let buf = inputs.streams.entry(stream.to_owned()).or_default();
+
+        if buf.closed {
+            return Err(StreamClosed);
+        }
+
+        if data.is_empty() {
+            buf.closed = true;
+        } else {
+            buf.data.extend(data);
+        }
+
+        self.arrived.notify_all();
+
+        Ok(())
+    }
+
+    // every input hits EOF, so nothing stays blocked on a client that's gone
+    pub fn shut(&self) {
+        self.inputs.lock().unwrap().shut = true;
+        self.arrived.notify_all();
+    }
+
+    pub fn reader(&self, stream: &str) -> StreamReader<'_> {
+        StreamReader {
+            streams: self,
+            stream: stream.to_owned(),
+            until: None
+        }
+    }
+
+    // false if it already was
+    pub fn close_output(&self, stream: &str) -> bool {
+        self.closed_outputs.lock().unwrap().insert(stream.to_owned())
+    }
+
+    pub fn output_closed(&self, stream: &str) -> bool {
+        self.closed_outputs.lock().unwrap().contains(stream)
+    }
+}
+
+pub struct StreamReader<'a> {
+    streams: &'a ClientStreams,
+    stream: String,
+    until: Option<Box<dyn Fn() -> bool + Send + 'a>> // runs dry once it's true, even if the stream's still open
+}
+
+impl<'a> StreamReader<'a> {
+    pub fn until(self, until: impl Fn() -> bool + Send + 'a) -> Self {
+        Self {
+            until: Some(Box::new(until)),
+            ..self
+        }
+    }
+}
+
+impl Read for StreamReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut inputs = self.streams.inputs.lock().unwrap();
+
+        loop {
+            let shut = inputs.shut || self.until.as_ref().is_some_and(|until| until());
+            let buf = inputs.streams.entry(self.stream.clone()).or_default();
+
+            if !buf.data.is_empty() || buf.closed || shut {
+                let len = out.len().min(buf.data.len());
+
+                for (byte, out) in buf.data.drain(..len).zip(out.iter_mut()) {
+                    *out = byte;
+                }
+
+                return Ok(len);
+            }
+
+            // nothing's going to notify about until, so it's checked every so often
+            inputs = match &self.until {
+                Some(_) => self.streams.arrived.wait_timeout(inputs, UNTIL_POLL).unwrap().0,
+                None => self.streams.arrived.wait(inputs).unwrap()
+            };
+        }
+    }
+}
+
+// where a directive's output goes; writes never fail, so a process is never left blocked on a full pipe, and whatever went wrong comes out of finish
+enum Sink<'a> {
+    Ignore,
+    Stream(&'a Container, String),
+    Capture(String, Vec<u8>),
+    File(String, File, Option<io::Error>),
+    Pipe(&'a ClientStreams, String),
+    Tee(Vec<Sink<'a>>),
+    Limited(Box<Sink<'a>>, u64), // bytes left
+    Gated(Box<Sink<'a>>, &'a Branches<'a>)
+}
+
+impl Write for Sink<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Ignore => {}
+            Sink::Stream(cont, stream) => if !cont.streams().output_closed(stream) {
+                cont.output(stream, buf);
+            }
+            Sink::Capture(_, captured) => captured.extend_from_slice(buf),
+            // whatever reads it has given up on it once it's closed
+            Sink::Pipe(pipes, pipe) => {
+                let _ = pipes.push(pipe, buf);
+            }
+            Sink::File(_, file, err) => if err.is_none() {
+                if let Err(write_err) = file.write_all(buf) {
+                    *err = Some(write_err);
+                }
+            }
+            Sink::Tee(sinks) => for sink in sinks {
+                sink.write_all(buf)?;
+            }
+            Sink::Limited(sink, left) => {
+                let len = buf.len().min(*left as usize);
+
+                sink.write_all(&buf[..len])?;
+                *left -= len as u64;
+            }
+            Sink::Gated(sink, branches) => if !branches.stopped(|branches| branches.stop_io) {
+                sink.write_all(buf)?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink<'_> {
+    // once the output is done: keeps what was captured
+    fn finish(self, captures: &mut Values) -> Result<(), StagingError> {
+        match self {
+            Sink::Ignore | Sink::Stream(..) => Ok(()),
+            Sink::Capture(name, captured) => {
+                captures.insert(name, captured);
+
+                Ok(())
+            }
+            Sink::Pipe(pipes, pipe) => {
+                let _ = pipes.push(&pipe, &[]);
+
+                Ok(())
+            }
+            Sink::File(file, _, Some(err)) => Err(StagingError::WriteFile(file, err)),
+            Sink::File(..) => Ok(()),
+            Sink::Tee(sinks) => sinks.into_iter().try_for_each(|sink| sink.finish(captures)),
+            Sink::Limited(sink, _) | Sink::Gated(sink, _) => sink.finish(captures)
+        }
+    }
+
+    // when the output stops short: whatever reads its pipes still gets to the end
+    fn abandon(self) {
+        let _ = self.finish(&mut Values::new());
+    }
+}
+
+// a source that runs dry once its simul stops file io
+struct Gated<'a> {
+    read: Box<dyn Read + Send + 'a>,
+    branches: &'a Branches<'a>
+}
+
+impl Read for Gated<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.branches.stopped(|branches| branches.stop_io) {
+            Ok(0)
+        } else {
+            self.read.read(buf)
+        }
+    }
+}
+
+// when a stream or pipe read should stop waiting for more, if ever: once until's set, or once a simul it's in stops file io
+// a read stop_io would cut off can be sat waiting on a client that never sends more, so it has to be woken for that as much as for until
+fn stop_reading<'s>(until: Option<&'s AtomicBool>, branches: Option<&'s Branches<'s>>) -> Option<impl Fn() -> bool + Send + Copy + 's> {
+    (until.is_some() || branches.is_some()).then_some(move || until.is_some_and(|until| until.load(Ordering::Relaxed)) || branches.is_some_and(|branches| branches.stopped(|branches| branches.stop_io)))
+}
+
+// what the branches of a running simul share
+struct Branches<'a> {
+    parent: Option<&'a Branches<'a>>, // the simul this one's a branch of, if any
+    failed: AtomicBool,
+    finish: bool,
+    kill: bool,
+    stop_io: bool
+}
+
+impl Branches<'_> {
+    // whether a branch failed under the policy, here or in any simul this one's nested in
+    fn stopped(&self, policy: fn(&Branches) -> bool) -> bool {
+        (policy(self) && self.failed.load(Ordering::Relaxed)) || self.parent.is_some_and(|parent| parent.stopped(policy))
+    }
+}
+
+// how a run with an id went
+#[derive(Clone, Copy, Debug)]
+struct Exit {
+    status: ExitStatus,
+    succeeded: bool
+}
+
+// what a child stage leaves behind once it's finished
+struct Done {
+    captures: Values,
+    exits: HashMap<String, Exit>,
+    spawned: HashMap<String, Arc<Container>>
+}
+
+// what a simul branch leaves behind once it's finished
+struct Branch {
+    outcome: BranchOutcome,
+    result: Result<(), StagingError>,
+    done: Done
+}
+
+// what a fork's cases start from: everything its base captured and spawned, and whether its runs failed
+#[derive(Clone, Default)]
+pub struct Inherited {
+    captures: Values,
+    exits: HashMap<String, Exit>,
+    spawned: HashMap<String, Arc<Container>>, // shared between the cases, as they are between simul branches
+    failed: bool
+}
+
+// one container's run through the directives, or one branch of a simul's
+pub struct Stage<'a> {
+    cont: &'a Container,
+    main: &'a Container, // the one staging started in, which holds the pipes
+    spawner: &'a dyn Spawn,
+    spawned: HashMap<String, Arc<Container>>, // by name
+    values: &'a Values,
+    captures: Values,
+    exits: HashMap<String, Exit>, // of runs with ids
+    branches: Option<&'a Branches<'a>>,
+    failed: bool, // a run didn't exit 0
+    cancelled: bool // stopped short by another branch
+}
+
+impl<'a> Stage<'a> {
+    pub fn new(cont: &'a Container, values: &'a Values, spawner: &'a dyn Spawn) -> Self {
+        Self {
+            cont,
+            main: cont,
+            spawner,
+            spawned: HashMap::new(),
+            values,
+            captures: Values::new(),
+            exits: HashMap::new(),
+            branches: None,
+            failed: false,
+            cancelled: false
+        }
+    }
+
+    pub fn forked(cont: &'a Container, values: &'a Values, spawner: &'a dyn Spawn, base: &Inherited) -> Self {
+        Self {
+            captures: base.captures.clone(),
+            exits: base.exits.clone(),
+            spawned: base.spawned.clone(),
+            failed: base.failed,
+            ..Self::new(cont, values, spawner)
+        }
+    }
+
+    // for directives nested in this stage's: the same run, with its own copy of what's happened so far, for absorb to take back
+    fn child<'b>(&'b self, cont: &'b Container, branches: Option<&'b Branches<'b>>) -> Stage<'b> {
+        Stage {
+            cont,
+            main: self.main,
+            spawner: self.spawner,
+            spawned: self.spawned.clone(),
+            values: self.values,
+            captures: self.captures.clone(),
+            exits: self.exits.clone(),
+            branches,
+            failed: false,
+            cancelled: false
+        }
+    }
+
+    fn done(self) -> Done {
+        Done {
+            captures: self.captures,
+            exits: self.exits,
+            spawned: self.spawned
+        }
+    }
+
+    fn absorb(&mut self, done: Done) {
+        self.captures.extend(done.captures);
+        self.exits.extend(done.exits);
+        self.spawned.extend(done.spawned);
+    }
+
+    fn container(&self, name: Option<&str>) -> Result<&Container, StagingError> {
+        match name {
+            None => Ok(self.cont),
+            Some("main") => Ok(self.main),
+            Some(name) => self.spawned.get(name).map(|cont| &**cont).ok_or_else(|| StagingError::UnknownContainer(name.to_owned()))
+        }
+    }
+
+    pub fn inherited(&self) -> Inherited {
+        Inherited {
+            captures: self.captures.clone(),
+            exits: self.exits.clone(),
+            spawned: self.spawned.clone(),
+            failed: self.failed
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    fn stopped(&self, policy: fn(&Branches) -> bool) -> bool {
+        self.branches.is_some_and(|branches| branches.stopped(policy))
+    }
+
+    fn fail(&mut self) {
+        self.failed = true;
+
+        if let Some(branches) = self.branches {
+            branches.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // what a directive reads from
+    // until, if given, is when streams and pipes it reads stop waiting for more
+    fn input<'s>(&'s self, source: &Source, until: Option<&'s AtomicBool>) -> Result<Box<dyn Read + Send + 's>, StagingError> {
+        let read = self.source(source, until)?;
+
+        Ok(match self.branches {
+            Some(branches) => Box::new(Gated {
+                read,
+                branches
+            }),
+            None => read
+        })
+    }
+
+    // every part is looked up before anything's read, so a missing value fails before a stream is half consumed
+    fn source<'s>(&'s self, source: &Source, until: Option<&'s AtomicBool>) -> Result<Box<dyn Read + Send + 's>, StagingError> {
+        let reader = |streams: &'a ClientStreams, stream: &str| match stop_reading(until, self.branches) {
+            Some(stopped) => streams.reader(stream).until(stopped),
+            None => streams.reader(stream)
+        };
+
+        Ok(match source {
+            Source::Const { value } => Box::new(Cursor::new(value.clone().into_bytes())),
+            Source::String { string } => match self.captures.get(string) {
+                Some(captured) => Box::new(Cursor::new(captured.clone())),
+                None => Box::new(Cursor::new(&self.values.get(string).ok_or_else(|| StagingError::UnknownValue(string.clone()))?[..]))
+            },
+            Source::Stream { stream } => Box::new(reader(self.cont.streams(), stream)),
+            Source::Pipe { pipe } => Box::new(reader(self.main.pipes(), pipe)),
+            Source::File { file, container } => Box::new(paths::open_file_in_root(&self.container(container.as_deref())?.root(), file, libc::O_RDONLY, 0).map_err(|err| StagingError::OpenFile(file.clone(), err))?),
+            Source::Concat { concat } => {
+                let mut read: Box<dyn Read + Send + 's> = Box::new(io::empty());
+
+                for part in concat {
+                    read = Box::new(read.chain(self.source(part, until)?));
+                }
+
+                read
+            }
+        })
+    }
+
+    // env vars' expanded values can't have an = in them, on top of what expand checks
+    fn args(&self, args: &[Arg], env: bool) -> Result<Vec<String>, StagingError> {
+        let mut built = Vec::new();
+
+        for arg in args {
+            match arg {
+                Arg::Literal(arg) => built.push(arg.clone()),
+                Arg::Source(source) => built.push(self.arg(source)?),
+                Arg::Expand(spec) => {
+                    let value = self.captures.get(&spec.expand).or_else(|| self.values.get(&spec.expand)).map(|value| &value[..]);
+
+                    if let Some(value) = value.filter(|value| env && value.contains(&b'=')) {
+                        return Err(StagingError::NotAllowed(String::from_utf8_lossy(value).into_owned()));
+                    }
+
+                    built.extend(expand(spec, value)?);
+                }
+            }
+        }
+
+        Ok(built)
+    }
+
+    fn arg(&self, source: &Source) -> Result<String, StagingError> {
+        let mut built = Vec::new();
+
+        self.source(source, None)?.read_to_end(&mut built).map_err(StagingError::ReadSource)?;
+
+        match String::from_utf8(built) {
+            Ok(built) if !built.contains('\0') => Ok(built),
+            Ok(built) => Err(StagingError::BadArg(built)),
+            Err(err) => Err(StagingError::BadArg(String::from_utf8_lossy(err.as_bytes()).into_owned()))
+        }
+    }
+
+    // files are opened here, before anything's run
+    fn sink(&self, dest: &Dest) -> Result<Sink<'a>, StagingError> {
+        let (sink, limit) = match dest {
+            Dest::Ignore => return Ok(Sink::Ignore),
+            Dest::Stream { stream, limit } => (Sink::Stream(self.cont, stream.clone()), limit),
+            Dest::Capture { capture, limit } => (Sink::Capture(capture.clone(), Vec::new()), limit),
+            Dest::Pipe { pipe, limit } => return Ok(Sink::Limited(Box::new(Sink::Pipe(self.main.pipes(), pipe.clone())), *limit)),
+            Dest::File { file, container, append, owner, limit } => {
+                let flags = libc::O_WRONLY | libc::O_CREAT | if *append { libc::O_APPEND } else { libc::O_TRUNC };
+                let out = paths::open_file_in_root(&self.container(container.as_deref())?.root(), file, flags, 0o644).map_err(|err| StagingError::OpenFile(file.clone(), err))?;
+
+                if let Some(owner) = owner {
+                    owner.apply(&out).map_err(|err| StagingError::OwnFile(file.clone(), err))?;
+                }
+
+                (Sink::File(file.clone(), out, None), limit)
+            }
+            Dest::Tee { tee, limit } => (Sink::Tee(tee.iter().map(|dest| self.sink(dest)).collect::<Result<_, _>>()?), limit)
+        };
+
+        Ok(match limit {
+            Some(limit) => Sink::Limited(Box::new(sink), *limit),
+            None => sink
+        })
+    }
+
+    // what a directive writes to
+    fn output(&self, dest: &Dest) -> Result<Sink<'a>, StagingError> {
+        let sink = self.sink(dest)?;
+
+        Ok(match self.branches {
+            Some(branches) => Sink::Gated(Box::new(sink), branches),
+            None => sink
+        })
+    }
+
+    pub fn run(&mut self, directives: &[Directive]) -> Result<(), StagingError> {
+        for directive in directives {
+            if self.stopped(|branches| branches.finish) {
+                self.cancelled = true;
+
+                break;
+            }
+
+            self.directive(directive)?;
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, condition: &Condition) -> Result<bool, StagingError> {
+        Ok(match condition {
+            Condition::Exited { run, codes } => matches!(self.exits.get(run), Some(Exit { status: ExitStatus::Code(code), .. }) if codes.contains(code)),
+            Condition::Succeeded { run } => self.exits.get(run).is_some_and(|exit| exit.succeeded),
+            Condition::FileExists { file } => match paths::open_in_root(&self.cont.root(), file, libc::O_PATH, 0) {
+                Ok(_) => true,
+                Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOTDIR)) => false,
+                Err(err) => return Err(StagingError::OpenFile(file.clone(), err))
+            },
+            Condition::Matches { capture, pattern } => self.captures.get(capture).is_some_and(|captured| glob(pattern.as_bytes(), captured)),
+            Condition::NonEmpty { capture } => self.captures.get(capture).is_some_and(|captured| !captured.is_empty()),
+            Condition::Flag { flag } => self.values.get(flag).is_some_and(|value| !value.is_empty()),
+            Condition::And { and } => {
+                for condition in and {
+                    if !self.check(condition)? {
+                        return Ok(false);
+                    }
+                }
+
+                true
+            }
+            Condition::Or { or } => {
+                for condition in or {
+                    if self.check(condition)? {
+                        return Ok(true);
+                    }
+                }
+
+                false
+            }
+            Condition::Not { not } => !self.check(not)?
+        })
+    }
+
+    fn simul(&mut self, simul: &[Vec<Directive>], branches: Branches) -> Result<(), StagingError> {
+        let results: Vec<Branch> = thread::scope(|scope| {
+            let handles: Vec<_> = simul.iter().map(|directives| {
+                let mut stage = self.child(self.cont, Some(&branches));
+                let branches = &branches;
+
+                scope.spawn(move || {
+                    let result = stage.run(directives);
+
+                    let outcome = if result.is_err() {
+                        branches.failed.store(true, Ordering::Relaxed);
+
+                        BranchOutcome::Errored
+                    } else if stage.failed {
+                        BranchOutcome::Failed
+                    } else if stage.cancelled {
+                        BranchOutcome::Cancelled
+                    } else {
+                        BranchOutcome::Succeeded
+                    };
+
+                    Branch {
+                        outcome,
+                        result,
+                        done: stage.done()
+                    }
+                })
+            }).collect();
+
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        self.cont.joined(&results.iter().map(|branch| branch.outcome.to_byte()).collect::<Vec<u8>>());
+
+        let mut errs: Vec<(usize, StagingError)> = Vec::new();
+        let mut outcomes: Vec<BranchOutcome> = Vec::new();
+
+        // in branch order, so a capture two branches both made comes out the same every time
+        for (branch_id, branch) in results.into_iter().enumerate() {
+            if let Err(err) = branch.result {
+                errs.push((branch_id, err));
+            }
+
+            outcomes.push(branch.outcome);
+            self.absorb(branch.done);
+        }
+
+        if !errs.is_empty() {
+            return Err(StagingError::Simul(errs));
+        }
+
+        if outcomes.contains(&BranchOutcome::Failed) {
+            self.fail();
+        } else if outcomes.contains(&BranchOutcome::Cancelled) {
+            self.cancelled = true;
+        }
+
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &Directive) -> Result<(), StagingError> {
+        match directive {
+            Directive::WriteFile { file, src, append, exists, owner } => {
+                let flags = libc::O_WRONLY | if *append { libc::O_APPEND } else { libc::O_TRUNC } | match exists {
+                    Some(true) => 0,
+                    Some(false) => libc::O_CREAT | libc::O_EXCL,
+                    None => libc::O_CREAT
+                };
+
+                let mut out = paths::open_file_in_root(&self.cont.root(), file, flags, 0o644).map_err(|err| StagingError::OpenFile(file.clone(), err))?;
+
+                if let Some(owner) = owner {
+                    owner.apply(&out).map_err(|err| StagingError::OwnFile(file.clone(), err))?;
+                }
+
+                io::copy(&mut self.input(src, None)?, &mut out).map_err(|err| StagingError::WriteFile(file.clone(), err))?;
+            }
+            Directive::OutputFile { file, dst } => {
+                let mut src = paths::open_file_in_root(&self.cont.root(), file, libc::O_RDONLY, 0).map_err(|err| StagingError::OpenFile(file.clone(), err))?;
+                let mut sink = self.output(dst)?;
+
+                if let Err(err) = io::copy(&mut src, &mut sink) {
+                    sink.abandon();
+
+                    return Err(StagingError::ReadFile(file.clone(), err));
+                }
+
+                sink.finish(&mut self.captures)?;
+            }
+            Directive::Run { id, run, args, cwd, user, env, stdin, stdout, stderr, ignore_code, success_codes, fail_codes, .. } => {
+                let env = self.args(env, true)?;
+
+                for var in &env {
+                    env_var(var)?;
+                }
+
+                let process = ProcessSpec {
+                    args: [run.clone()].into_iter().chain(self.args(args, false)?).collect(),
+                    env,
+                    cwd: cwd.clone(),
+                    user: user.clone()
+                };
+
+                // a stream stdin reads from could stay open long after the process is gone
+                let exited = AtomicBool::new(false);
+                let stdin: Box<dyn Read + Send> = match stdin {
+                    Some(source) => self.input(source, Some(&exited))?,
+                    None => Box::new(io::empty())
+                };
+
+                let mut stdout = stdout.as_ref().map_or(Ok(Sink::Ignore), |dest| self.output(dest))?;
+                let mut stderr = match stderr.as_ref().map_or(Ok(Sink::Ignore), |dest| self.output(dest)) {
+                    Ok(sink) => sink,
+                    Err(err) => {
+                        stdout.abandon();
+
+                        return Err(err);
+                    }
+                };
+
+                let branches = self.branches;
+                let kill = move || branches.is_some_and(|branches| branches.stopped(|branches| branches.kill));
+
+                let status = match self.cont.exec(&process, stdin, &mut stdout, &mut stderr, branches.map(|_| &kill as &(dyn Fn() -> bool + Sync)), &exited) {
+                    Ok(status) => status,
+                    Err(err) => {
+                        stdout.abandon();
+                        stderr.abandon();
+
+                        return Err(StagingError::Exec(run.clone(), err));
+                    }
+                };
+
+                stdout.finish(&mut self.captures)?;
+                stderr.finish(&mut self.captures)?;
+
+                let succeeded = succeeded(status, *ignore_code, success_codes.as_deref(), fail_codes.as_deref());
+
+                if let Some(id) = id {
+                    self.exits.insert(id.clone(), Exit { status, succeeded });
+                }
+
+                if status == ExitStatus::Signal(libc::SIGKILL) && self.stopped(|branches| branches.kill) {
+                    self.cancelled = true;
+                } else if !succeeded {
+                    self.fail();
+                }
+            }
+            Directive::CloseStream { stream } => {
+                if self.cont.streams().close_output(stream) {
+                    self.cont.output(stream, &[]);
+                }
+            }
+            Directive::Conditional { condition, directives } => if self.check(condition)? {
+                self.run(directives)?;
+            }
+            Directive::SpawnContainer { name, diffs, container_config, directives } => {
+                if self.spawned.contains_key(name) {
+                    return Err(StagingError::NameTaken(name.clone()));
+                }
+
+                let cont = self.spawner.spawn(diffs, container_config)?;
+                let mut cancelled = false;
+                let mut done = None;
+
+                let outcome = cont.stage(|cont| {
+                    let mut stage = self.child(cont, self.branches);
+                    let result = stage.run(directives);
+                    let failed = stage.failed;
+
+                    cancelled = stage.cancelled;
+                    done = Some(stage.done());
+                    result.map(|()| failed)
+                }).map_err(|err| StagingError::Spawned(name.clone(), Box::new(err)))?;
+
+                if let Some(done) = done {
+                    self.absorb(done);
+                }
+
+                self.spawned.insert(name.clone(), cont);
+
+                if matches!(outcome, Outcome::Failed | Outcome::DiskQuotaExceeded) {
+                    self.fail();
+                } else if cancelled {
+                    self.cancelled = true;
+                }
+            }
+            // the instance runs it, it needs containers a stage doesn't have
+            Directive::ForkCases { .. } => return Err(StagingError::BadDirective("fork_cases anywhere but last at the top".to_owned())),
+            Directive::Simul { simul, finish_on_first_fail, kill_on_first_fail, stop_file_io_on_first_fail } => self.simul(simul, Branches {
+                parent: self.branches,
+                failed: AtomicBool::new(false),
+                finish: *finish_on_first_fail,
+                kill: *kill_on_first_fail,
+                stop_io: *stop_file_io_on_first_fail
+            })?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn parse_and_validate() {
+        let directives: Vec<Directive> = serde_json::from_str(r#"[
+            {"type": "write_file", "file": "/tmp/main.py", "owner": {"uid": 1000, "gid": 1000, "mode": 420}, "src": {"type": "concat", "concat": [{"type": "const", "const": "import sys\n"}, {"type": "string", "string": "code"}]}},
+            {"type": "run", "run": "python3", "user": {"uid": 1000, "gid": 1000, "umask": 18}, "args": ["/tmp/main.py", {"type": "string", "string": "arg"}], "unchecked_args": true, "stdin": {"type": "stream", "stream": "stdin"}, "stdout": {"type": "tee", "tee": [{"type": "stream", "stream": "stdout"}, {"type": "capture", "capture": "out", "limit": 4096}]}},
+            {"type": "conditional", "condition": {"type": "and", "and": [{"type": "exited", "run": "compile", "codes": [0]}, {"type": "not", "not": {"type": "flag", "flag": "O2"}}]}, "directives": []},
+            {"type": "spawn_container", "name": "checker", "diffs": ["sha256:0000000000000000000000000000000000000000000000000000000000000000"], "container_config": {"env": ["MODE=check"]}, "directives": [
+                {"type": "write_file", "file": "/tmp/out", "src": {"type": "file", "file": "/tmp/out", "container": "main"}},
+                {"type": "run", "run": "check", "stdin": {"type": "pipe", "pipe": "answers"}}
+            ]},
+            {"type": "close_stream", "stream": "stdout"}
+        ]"#).unwrap();
+
+        assert!(validate(&directives).is_ok());
+        assert!(validate(&[Directive::OutputFile { file: "out".to_owned(), dst: Dest::Ignore }]).is_err());
+        assert!(validate(&[Directive::SpawnContainer { name: "main".to_owned(), diffs: vec!["sha256:00".to_owned()], container_config: SpawnConfig::default(), directives: Vec::new() }]).is_err());
+        assert!(validate(&[Directive::ForkCases { directives: Vec::new(), simul: false }]).is_ok());
+        assert!(validate(&[Directive::ForkCases { directives: Vec::new(), simul: false }, Directive::CloseStream { stream: "stdout".to_owned() }]).is_err());
+        assert!(serde_json::from_str::<Directive>(r#"{"type": "run", "run": "sh", "shell": true}"#).is_err());
+        assert!(serde_json::from_str::<Dest>(r#"{"type": "pipe", "pipe": "answers", "limit": 65536}"#).is_ok());
+        assert!(serde_json::from_str::<Dest>(r#"{"type": "pipe", "pipe": "answers"}"#).is_err());
+        assert!(validate(&[serde_json::from_str(r#"{"type": "run", "run": "sh", "user": {"uid": 1000, "gid": 1000, "umask": 4095}}"#).unwrap()]).is_err());
+        assert!(validate(&[serde_json::from_str(r#"{"type": "spawn_container", "name": "checker", "diffs": ["sha256:0000000000000000000000000000000000000000000000000000000000000000"], "container_config": {"user": {"uid": 1000, "gid": 1000, "umask": 1024}}, "directives": []}"#).unwrap()]).is_err());
+
+        // what the client sends only gets into args and env checked, unless the run says otherwise
+        for (run, ok) in [
+            (r#"{"type": "run", "run": "sh", "args": [{"type": "string", "string": "arg"}]}"#, false),
+            (r#"{"type": "run", "run": "sh", "env": [{"type": "concat", "concat": [{"type": "const", "const": "A="}, {"type": "stream", "stream": "stdin"}]}]}"#, false),
+            (r#"{"type": "run", "run": "sh", "args": [{"type": "concat", "concat": [{"type": "const", "const": "-"}, {"type": "const", "const": "c"}]}]}"#, true),
+            (r#"{"type": "run", "run": "sh", "env": [{"expand": "flags"}]}"#, false),
+            (r#"{"type": "run", "run": "sh", "env": [{"expand": "flags", "template": "{}=1"}]}"#, false),
+            (r#"{"type": "run", "run": "sh", "env": [{"expand": "flags", "template": "FLAGS={}", "split": "whitespace", "join": " "}]}"#, true)
+        ] {
+            assert_eq!(validate(&[serde_json::from_str(run).unwrap()]).is_ok(), ok, "{}", run);
+        }
+    }
+
+    #[test]
+    fn sinks_capture_up_to_limit() {
+        let mut sink = Sink::Tee(vec![Sink::Capture("all".to_owned(), Vec::new()), Sink::Limited(Box::new(Sink::Capture("head".to_owned(), Vec::new())), 3)]);
+        let mut captures = Values::new();
+
+        sink.write_all(b"ab").unwrap();
+        sink.write_all(b"cdef").unwrap();
+        sink.finish(&mut captures).unwrap();
+
+        assert_eq!(captures["all"], b"abcdef");
+        assert_eq!(captures["head"], b"abc");
+    }
+
+    #[test]
+    fn expands_only_allowed_words() {
+        let flags: Expand = serde_json::from_str(r#"{"expand": "flags", "split": "whitespace", "allow": ["-O?", "-W*"], "max": 3}"#).unwrap();
+
+        assert_eq!(expand(&flags, Some(b" -O2  -Wall\n")).unwrap(), ["-O2", "-Wall"]);
+        assert_eq!(expand(&flags, None).unwrap(), Vec::<String>::new());
+        assert!(matches!(expand(&flags, Some(b"-O2 -fplugin=evil.so")), Err(StagingError::NotAllowed(_))));
+        assert!(matches!(expand(&flags, Some(b"-O1 -O2 -O3 -Os")), Err(StagingError::TooManyWords(..))));
+
+        // without an allowlist, anything goes but flags
+        let argv: Expand = serde_json::from_str(r#"{"expand": "argv", "split": "lines"}"#).unwrap();
+
+        assert_eq!(expand(&argv, Some(b"a b\nc")).unwrap(), ["a b", "c"]);
+        assert!(expand(&argv, Some(b"a\n--help")).is_err());
+
+        let script: Expand = serde_json::from_str(r#"{"expand": "name", "allow": ["*"], "escape": "shell", "template": "echo {}"}"#).unwrap();
+
+        assert_eq!(expand(&script, Some(b"it's; rm -rf /")).unwrap(), ["echo 'it'\\''s; rm -rf /'"]);
+
+        let env: Expand = serde_json::from_str(r#"{"expand": "flags", "split": "whitespace", "allow": ["-O?"], "join": " ", "template": "CFLAGS={}"}"#).unwrap();
+
+        assert_eq!(expand(&env, Some(b"-O2 -O3")).unwrap(), ["CFLAGS=-O2 -O3"]);
+        assert!(validate_arg(&Arg::Expand(Expand { template: "-D{}={}".to_owned(), ..env })).is_err());
+    }
+
+    #[test]
+    fn runs_succeed_by_their_codes() {
+        assert!(succeeded(ExitStatus::Code(0), false, None, None));
+        assert!(!succeeded(ExitStatus::Code(1), false, None, None));
+        assert!(succeeded(ExitStatus::Code(1), false, Some(&[0, 1]), None));
+        assert!(!succeeded(ExitStatus::Code(0), false, Some(&[1]), None));
+        assert!(succeeded(ExitStatus::Code(3), false, None, Some(&[1, 2])));
+        assert!(!succeeded(ExitStatus::Code(2), false, None, Some(&[1, 2])));
+        assert!(!succeeded(ExitStatus::Signal(libc::SIGKILL), false, None, Some(&[1])));
+        assert!(succeeded(ExitStatus::Signal(libc::SIGSEGV), true, None, None));
+
+        assert!(validate(&[serde_json::from_str(r#"{"type": "run", "run": "lint", "success_codes": [0, 1]}"#).unwrap()]).is_ok());
+        assert!(validate(&[serde_json::from_str(r#"{"type": "run", "run": "lint", "ignore_code": true, "fail_codes": [2]}"#).unwrap()]).is_err());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*", b""));
+        assert!(glob(b"ok*", b"ok\n"));
+        assert!(glob(b"*error*", b"main.c:1: error: x"));
+        assert!(glob(b"a?c", b"abc"));
+        assert!(glob(b"*a*b", b"xaxxab"));
+
+        assert!(!glob(b"ok", b"ok\n"));
+        assert!(!glob(b"a?c", b"ac"));
+        assert!(!glob(b"*a*b", b"xaxxa"));
+    }
+
+    #[test]
+    fn policies_reach_nested_simuls() {
+        let outer = Branches {
+            parent: None,
+            failed: AtomicBool::new(false),
+            finish: true,
+            kill: false,
+            stop_io: false
+        };
+        let inner = Branches {
+            parent: Some(&outer),
+            failed: AtomicBool::new(false),
+            finish: false,
+            kill: true,
+            stop_io: false
+        };
+
+        assert!(!inner.stopped(|branches| branches.finish));
+
+        outer.failed.store(true, Ordering::Relaxed);
+        assert!(inner.stopped(|branches| branches.finish));
+        assert!(!inner.stopped(|branches| branches.kill));
+
+        inner.failed.store(true, Ordering::Relaxed);
+        assert!(inner.stopped(|branches| branches.kill));
+        assert!(!inner.stopped(|branches| branches.stop_io));
+    }
+
+    #[test]
+    fn streams_read_until_closed() {
+        let streams = ClientStreams::default();
+        let mut out = Vec::new();
+
+        streams.push("stdin", b"abc").unwrap();
+        streams.push("stdin", b"").unwrap();
+        assert!(streams.push("stdin", b"d").is_err());
+
+        streams.reader("stdin").read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abc");
+
+        // still open, but whoever wanted it has stopped waiting
+        let exited = AtomicBool::new(true);
+
+        out.clear();
+        streams.reader("open").until(|| exited.load(Ordering::Relaxed)).read_to_end(&mut out).unwrap();
+        assert!(out.is_empty());
+
+        assert!(stop_reading(None, None).is_none());
+
+        // nothing was ever sent on it, but it's not left hanging once everything's shut
+        streams.shut();
+        out.clear();
+        streams.reader("other").read_to_end(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn failed_siblings_wake_blocked_reads() {
+        let streams = ClientStreams::default();
+        let simul = Branches {
+            parent: None,
+            failed: AtomicBool::new(false),
+            finish: false,
+            kill: false,
+            stop_io: true
+        };
+        let (done_tx, done_rx) = mpsc::channel();
+
+        streams.push("stdin", b"abc").unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut out = Vec::new();
+
+                // the client never sends more or closes it
+                streams.reader("stdin").until(stop_reading(None, Some(&simul)).unwrap()).read_to_end(&mut out).unwrap();
+                done_tx.send(out).unwrap();
+            });
+
+            assert!(done_rx.recv_timeout(UNTIL_POLL * 10).is_err());
+
+            // a sibling branch failing
+            simul.failed.store(true, Ordering::Relaxed);
+
+            let out = done_rx.recv_timeout(Duration::from_secs(5));
+
+            // unblocks it either way, so a failure doesn't leave the scope hanging
+            streams.shut();
+
+            assert_eq!(out.unwrap(), b"abc");
+        });
+    }
+}